@@ -1,69 +1,993 @@
 use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
+use std::os::unix::io::RawFd;
+use prometheus::IntGaugeVec;
+use super::uevent::UeventSocket;
+use super::mounts::Mounts;
+use super::zoned::Zones;
+use super::errors::{Result, ResultExt};
+use super::log;
+
+/// Which devices in a dm/md stack to report latencies for.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DeviceLevel {
+    All,
+    Leaf,       // devices that are not built on top of other devices (physical disks)
+    TopLevel,   // devices that nothing else is built on top of (LVs, md arrays, ...)
+}
+
+impl DeviceLevel {
+    pub fn from_str(level: &str) -> Option<Self> {
+        match level {
+            "all"       => Some(DeviceLevel::All),
+            "leaf"      => Some(DeviceLevel::Leaf),
+            "top-level" => Some(DeviceLevel::TopLevel),
+            _ => None
+        }
+    }
+}
+
+/// What to use as the "device" label.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DeviceLabel {
+    Path,       // /dev/sda, /dev/vg/lv
+    ById,       // /dev/disk/by-id/...
+    ByPath,     // /dev/disk/by-path/...
+    ByUuid,     // /dev/disk/by-uuid/...
+    ByPartuuid, // /dev/disk/by-partuuid/...
+    Wwn,
+    Serial,
+}
+
+impl DeviceLabel {
+    pub fn from_str(label: &str) -> Option<Self> {
+        match label {
+            "path"        => Some(DeviceLabel::Path),
+            "by-id"       => Some(DeviceLabel::ById),
+            "by-path"     => Some(DeviceLabel::ByPath),
+            "by-uuid"     => Some(DeviceLabel::ByUuid),
+            "by-partuuid" => Some(DeviceLabel::ByPartuuid),
+            "wwn"         => Some(DeviceLabel::Wwn),
+            "serial"      => Some(DeviceLabel::Serial),
+            _ => None
+        }
+    }
+}
+
+/// Which device to attribute requests to.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Partition,  // the device the request was traced against
+    Disk,       // the whole disk if that was a partition
+    TopLevel,   // the device at the top of the dm/md stack
+}
+
+impl Aggregation {
+    pub fn from_str(aggregation: &str) -> Option<Self> {
+        match aggregation {
+            "partition" => Some(Aggregation::Partition),
+            "disk"      => Some(Aggregation::Disk),
+            "top-level" => Some(Aggregation::TopLevel),
+            _ => None
+        }
+    }
+}
+
+/// How to divide devices into LBA regions.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Regions {
+    None,
+    Count(u64),     // this many equally sized regions
+    Partitions,     // one region per partition
+}
+
+impl Regions {
+    pub fn from_str(regions: &str) -> Option<Self> {
+        match regions {
+            "partitions" => Some(Regions::Partitions),
+            count => match count.parse() {
+                Ok(0) => None,
+                Ok(count) => Some(Regions::Count(count)),
+                Err(_) => None
+            }
+        }
+    }
+}
+
+/// How devices are to be reported.
+#[derive(Clone)]
+pub struct Options {
+    pub level: DeviceLevel,
+    pub aggregation: Aggregation,
+    pub label: DeviceLabel,
+    pub kernel_name_label: bool,
+    pub mountpoint_label: bool,
+    pub zone_type_label: bool,
+    pub regions: Regions,
+    pub include: Vec<String>,   // kernel names; empty means all devices
+    pub exclude: Vec<String>,
+}
+
+struct Device {
+    label: String,
+    name: String,
+    mountpoints: String,
+    wanted: bool,
+    zoned: bool,
+    zones: Option<Zones>,
+    size: u64,  // in sectors
+    physical_block_size: u64,   // in bytes
+    partitions: Vec<(u64, u64, String)>,  // start sector, end sector, name
+    // Kernel names of the devices whose info we're holding up, see Published
+    published: Vec<String>,
+    // Kernel names of the devices whose changes affect this one, see stack()
+    related: Vec<String>,
+}
+
+/// Info metrics we've set for a device, so we can remove them when it goes away.
+///
+/// All members of a disk or stack that we aggregate to it report as the same
+/// device, so its info stays around for as long as any of them is cached.
+struct Published {
+    users: usize,
+    label: String,
+    series: Vec<(IntGaugeVec, Vec<String>)>,
+    // The "major,minor" we've published mount info for, if any
+    mount_dev: Option<String>,
+    mountpoints: String,
+    mount_series: Vec<(IntGaugeVec, Vec<String>)>,
+}
 
 pub struct DevicePaths {
-    cache: HashMap<String, String>,
-    proc_partitions: String
+    cache: HashMap<String, Device>,
+    published: HashMap<String, Published>,    // by kernel name
+    proc_partitions: String,
+    uevents: Option<UeventSocket>,
+    mounts: Mounts,
+    options: Options,
+    g_device_info: IntGaugeVec,
+    g_mount_info: IntGaugeVec,
+    g_nvme_info: IntGaugeVec,
+}
+
+fn set_series(series: &mut Vec<(IntGaugeVec, Vec<String>)>, gauge: &IntGaugeVec, labels: Vec<String>) {
+    let label_refs: Vec<&str> = labels.iter().map(String::as_str).collect();
+    gauge.with_label_values(&label_refs).set(1);
+    series.push((gauge.clone(), labels));
+}
+
+fn remove_series(series: Vec<(IntGaugeVec, Vec<String>)>) {
+    for (gauge, labels) in series {
+        let label_refs: Vec<&str> = labels.iter().map(String::as_str).collect();
+        let _ = gauge.remove_label_values(&label_refs);
+    }
+}
+
+fn sysfs_list(name: &str, what: &str) -> Vec<String> {
+    // /sys/class/block/<name>/{holders,slaves} contain one symlink per device
+    match fs::read_dir(format!("/sys/class/block/{}/{}", name, what)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| String::from(entry.file_name().to_string_lossy()))
+            .collect(),
+        Err(_) => vec![]
+    }
+}
+
+fn partitions(name: &str) -> Vec<String> {
+    // Partitions show up as subdirectories of the disk that have a "partition" file
+    match fs::read_dir(format!("/sys/class/block/{}", name)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("partition").exists())
+            .map(|entry| String::from(entry.file_name().to_string_lossy()))
+            .collect(),
+        Err(_) => vec![]
+    }
+}
+
+/// Devices built on top of this one, including those built on top of its partitions.
+///
+/// Requests on partitions are usually traced against the whole disk, so from our
+/// point of view, an LV on sda2 is being held by sda.
+fn holders(name: &str) -> Vec<String> {
+    let mut holders = sysfs_list(name, "holders");
+    for part in partitions(name) {
+        holders.extend(sysfs_list(&part, "holders"));
+    }
+    holders
+}
+
+fn slaves(name: &str) -> Vec<String> {
+    sysfs_list(name, "slaves")
+}
+
+fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(dir.join(attr))
+        .ok()
+        .map(|value| String::from(value.trim()))
+        .filter(|value| !value.is_empty())
+}
+
+/// The sysfs directory of the whole disk, since partitions have no queue/ or device/.
+fn disk_dir(name: &str) -> std::path::PathBuf {
+    let dir = Path::new("/sys/class/block").join(name);
+    if dir.join("partition").exists() {
+        if let Some(parent) = fs::canonicalize(&dir).ok().and_then(|path| path.parent().map(Path::to_path_buf)) {
+            return parent;
+        }
+    }
+    dir
+}
+
+fn transport(name: &str, dir: &Path) -> String {
+    if name.starts_with("nvme") {
+        // device/ points to the controller, which knows if it's pcie, tcp, rdma or fc
+        return match read_attr(dir, "device/transport") {
+            Some(transport) => format!("nvme-{}", transport),
+            None => String::from("nvme")
+        };
+    }
+    for (prefix, transport) in &[("dm-", "dm"), ("md", "md"), ("loop", "loop"), ("vd", "virtio")] {
+        if name.starts_with(prefix) {
+            return String::from(*transport);
+        }
+    }
+    // Look at the bus the device hangs off of
+    let sysfs_path = fs::canonicalize(dir)
+        .map(|path| String::from(path.to_string_lossy()))
+        .unwrap_or_default();
+    for (marker, transport) in &[("/usb", "usb"), ("/ata", "ata"), ("/virtio", "virtio"), ("/host", "scsi")] {
+        if sysfs_path.contains(marker) {
+            return String::from(*transport);
+        }
+    }
+    String::new()
+}
+
+fn serial(dir: &Path) -> Option<String> {
+    read_attr(dir, "serial").or_else(|| read_attr(dir, "device/serial"))
+}
+
+fn wwid(dir: &Path) -> Option<String> {
+    read_attr(dir, "wwid")
+        .or_else(|| read_attr(dir, "device/wwid"))
+        .or_else(|| read_attr(dir, "dm/uuid"))
+}
+
+/// Find the symlinks in /dev/disk/<kind> that point to /dev/<name>, sorted by name.
+fn disk_links(kind: &str, name: &str) -> Vec<String> {
+    let target = Path::new("/dev").join(name);
+    let mut links: Vec<String> = match fs::read_dir(Path::new("/dev/disk").join(kind)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| fs::canonicalize(entry.path()).map(|path| path == target).unwrap_or(false))
+            .map(|entry| String::from(entry.path().to_string_lossy()))
+            .collect(),
+        Err(_) => vec![]
+    };
+    links.sort();
+    links
+}
+
+/// Model, vendor etc of a device, in the order of the diskio_device_info labels after top_level.
+fn device_attrs(name: &str) -> Vec<String> {
+    let dir = disk_dir(name);
+    let attr = |attr: &str| read_attr(&dir, attr).unwrap_or_default();
+    // The scheduler file lists all of them and has the active one in brackets
+    let scheduler = attr("queue/scheduler")
+        .split_ascii_whitespace()
+        .find(|sched| sched.starts_with('['))
+        .map(|sched| sched.trim_matches(|c| c == '[' || c == ']').to_string())
+        .unwrap_or_else(|| attr("queue/scheduler"));
+    vec![
+        attr("device/model"),
+        attr("device/vendor"),
+        serial(&dir).unwrap_or_default(),
+        wwid(&dir).unwrap_or_default(),
+        attr("queue/rotational"),
+        scheduler,
+        attr("queue/nr_requests"),
+        attr("queue/logical_block_size"),
+        attr("queue/physical_block_size"),
+        transport(name, &dir),
+        attr("queue/zoned"),
+        attr("queue/nr_zones"),
+    ]
+}
+
+fn is_zoned(name: &str) -> bool {
+    match read_attr(&disk_dir(name), "queue/zoned") {
+        Some(zoned) => zoned != "none",
+        None => false
+    }
+}
+
+/// Controller, namespace ID and multipath head of an NVMe namespace.
+///
+/// With native NVMe multipathing, nvme0n1 is the multipath head device, and
+/// requests are sent through the hidden path devices nvme0c0n1, nvme0c1n1 etc.
+/// (nvme<subsystem>c<controller>n<namespace>). Path devices are not listed in
+/// /proc/partitions, but they show up in the trace.
+fn nvme_info(name: &str) -> Option<(String, String, String)> {
+    if !name.starts_with("nvme") {
+        return None;
+    }
+    let dir = Path::new("/sys/class/block").join(name);
+    let nsid = read_attr(&dir, "nsid").unwrap_or_default();
+    let numbers: Vec<&str> = name["nvme".len()..]
+        .split(['c', 'n'])
+        .collect();
+    if let [subsys, ctrl, ns] = numbers[..] {
+        // path device
+        return Some((format!("nvme{}", ctrl), nsid, format!("nvme{}n{}", subsys, ns)));
+    }
+    // Either a namespace on a controller without multipathing, where device/ points
+    // to the controller, or a head device, where it points to the subsystem
+    let controller = fs::canonicalize(dir.join("device"))
+        .ok()
+        .and_then(|path| path.file_name().map(|ctrl| String::from(ctrl.to_string_lossy())))
+        .unwrap_or_default();
+    Some((controller, nsid, String::new()))
+}
+
+fn sectors_attr(name: &str, attr: &str) -> Option<u64> {
+    read_attr(&Path::new("/sys/class/block").join(name), attr)
+        .and_then(|value| value.parse().ok())
+}
+
+/// Start and end sectors of the partitions of a disk.
+fn partition_ranges(name: &str) -> Vec<(u64, u64, String)> {
+    let mut ranges: Vec<(u64, u64, String)> = partitions(name)
+        .into_iter()
+        .filter_map(|part| {
+            let start = sectors_attr(&part, "start")?;
+            let size = sectors_attr(&part, "size")?;
+            Some((start, start + size, part))
+        })
+        .collect();
+    ranges.sort();
+    ranges
+}
+
+/// The whole disk that a partition belongs to.
+fn whole_disk(name: &str) -> Option<String> {
+    if !Path::new("/sys/class/block").join(name).join("partition").exists() {
+        return None;
+    }
+    disk_dir(name).file_name().map(|disk| String::from(disk.to_string_lossy()))
+}
+
+fn majmin(name: &str) -> Option<String> {
+    // /sys/class/block/<name>/dev contains "major:minor"
+    fs::read_to_string(format!("/sys/class/block/{}/dev", name))
+        .ok()
+        .map(|majmin| majmin.trim().replace(':', ","))
+}
+
+/// The devices at the top of the stack(s) that this device is a member of.
+fn top_levels(name: &str) -> Vec<String> {
+    let holders = holders(name);
+    if holders.is_empty() {
+        return vec![String::from(name)];
+    }
+    holders.iter().flat_map(|holder| top_levels(holder)).collect()
+}
+
+/// The device, its whole disk, and everything stacked on top of or below them.
+///
+/// Adding or removing any of these can change how we aggregate and label the
+/// device, and the holders and top levels in its info.
+fn stack(name: &str) -> Vec<String> {
+    let mut stack = vec![String::from(name)];
+    let mut idx = 0;
+    while idx < stack.len() {
+        let name = stack[idx].clone();
+        for related in whole_disk(&name).into_iter().chain(holders(&name)).chain(slaves(&name)) {
+            if !stack.contains(&related) {
+                stack.push(related);
+            }
+        }
+        idx += 1;
+    }
+    stack
+}
+
+/// Split a device mapper name like "vg--name-lv--name" into VG and LV name.
+fn split_vg_lv(name: &str) -> Option<(String, String)> {
+    let bytes = name.as_bytes();
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'-' {
+            if bytes.get(idx + 1) == Some(&b'-') {
+                idx += 2;
+                continue;
+            }
+            if idx == 0 || idx + 1 == bytes.len() {
+                return None;
+            }
+            return Some((name[..idx].replace("--", "-"), name[idx + 1..].replace("--", "-")));
+        }
+        idx += 1;
+    }
+    None
 }
 
 impl DevicePaths {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(options: Options) -> Result<Self> {
+        Ok(Self {
             cache: HashMap::new(),
-            proc_partitions: fs::read_to_string("/proc/partitions")
-                .expect("cannot read /proc/partitions")
-        }
+            published: HashMap::new(),
+            // If we can't read this, kernel_name() falls back to sysfs
+            proc_partitions: fs::read_to_string("/proc/partitions").unwrap_or_default(),
+            // Without uevents we still notice new devices when we fail to look them up,
+            // we just won't notice when a major:minor gets reused
+            uevents: match UeventSocket::open() {
+                Ok(uevents) => Some(uevents),
+                Err(err) => {
+                    log::warn("Could not listen for uevents, reused device numbers may be mislabeled",
+                              &[("error", &err.to_string())]);
+                    None
+                }
+            },
+            mounts: Mounts::new(),
+            options,
+            g_device_info: register_int_gauge_vec!(
+                "diskio_device_info",
+                "Device properties from sysfs, and which device is built on top of which",
+                &["device", "parent", "top_level", "model", "vendor", "serial", "wwid",
+                  "rotational", "scheduler", "nr_requests", "logical_block_size",
+                  "physical_block_size", "transport", "zoned", "nr_zones"]
+            ).chain_err(|| "Couldn't set up device info gauge")?,
+            g_mount_info: register_int_gauge_vec!(
+                "diskio_device_mount_info",
+                "Mount points of devices or their partitions",
+                &["device", "partition", "mountpoint", "fstype"]
+            ).chain_err(|| "Couldn't set up mount info gauge")?,
+            g_nvme_info: register_int_gauge_vec!(
+                "diskio_nvme_namespace_info",
+                "NVMe controller, namespace ID and multipath head of NVMe devices",
+                &["device", "controller", "nsid", "head"]
+            ).chain_err(|| "Couldn't set up NVMe info gauge")?,
+        })
     }
 
-    fn resolve(&self, dev: &str) -> std::io::Result<String> {
-        let parts: Vec<&str> = dev.split(",").collect();
+    fn kernel_name(&self, dev: &str) -> Option<String> {
+        let parts: Vec<&str> = dev.split(',').collect();
+        if parts.len() != 2 {
+            return None;
+        }
         let (major, minor) = (parts[0], parts[1]);
-        let mut name = None;
 
         if major == "7" {
             // These are not listed in /proc/partitions but generate events
-            return Ok(format!("/dev/loop{}", minor));
+            return Some(format!("loop{}", minor));
         }
 
+        let mut name = None;
         for line in self.proc_partitions.lines().skip(2) {
             let fields: Vec<&str> = line.split_ascii_whitespace().collect();
-            if fields[0] == major && fields[1] == minor {
-                name = Some(fields[3].clone());
+            if fields.len() >= 4 && fields[0] == major && fields[1] == minor {
+                name = Some(String::from(fields[3]));
             }
         }
-        let name = name
-            .expect(&format!("Device with no name: {}", dev));
+        // Hidden devices (like NVMe multipath path devices) are not in /proc/partitions,
+        // but sysfs knows them
+        name.or_else(|| {
+            fs::read_link(format!("/sys/dev/block/{}:{}", major, minor))
+                .ok()
+                .and_then(|path| path.file_name().map(|name| String::from(name.to_string_lossy())))
+        })
+    }
+
+    fn resolve(&self, dev: &str) -> std::io::Result<String> {
+        let name = match self.kernel_name(dev) {
+            Some(name) => name,
+            // Use "major:minor" so that we at least have something to go by
+            None => return Ok(dev.replace(',', ":"))
+        };
         let mut dev_path = format!("/dev/{}", name);
 
         if name.starts_with("dm-") {
-            for path in fs::read_dir("/dev/mapper")? {
-                let link_target = fs::read_link(&path?.path().as_path())?;
-                if link_target.as_path() == Path::new(&dev_path) {
-                    let file_name = String::from(link_target.file_name().unwrap().to_string_lossy());
-                    if file_name.contains("-") {
-                        let parts: Vec<&str> = file_name.splitn(2, "-").collect();
-                        let vg = String::from(parts[0]);
-                        let lv = String::from(parts[1]).replace("--", "-");
-                        dev_path = format!("/dev/{}/{}", vg, lv);
-                    } else {
-                        dev_path = String::from(link_target.to_string_lossy());
-                    }
+            // Only LVM names are "vg-lv", others (dm-crypt, multipath, ...) are used as they are
+            let is_lvm = read_attr(&Path::new("/sys/class/block").join(&name), "dm/uuid")
+                .map(|uuid| uuid.starts_with("LVM-"))
+                .unwrap_or(false);
+            // Entries that vanish while we're looking or aren't links (like "control")
+            // are just skipped. Link targets are usually relative, like "../dm-0".
+            for entry in fs::read_dir("/dev/mapper")?.filter_map(|entry| entry.ok()) {
+                match fs::symlink_metadata(entry.path()) {
+                    Ok(meta) if meta.file_type().is_symlink() => (),
+                    _ => continue
                 }
+                match fs::canonicalize(entry.path()) {
+                    Ok(target) if target.as_path() == Path::new(&dev_path) => (),
+                    _ => continue
+                }
+                let file_name = String::from(entry.file_name().to_string_lossy());
+                // LVM escapes dashes in VG and LV names by doubling them
+                dev_path = match split_vg_lv(&file_name).filter(|_| is_lvm) {
+                    Some((vg, lv)) => format!("/dev/{}/{}", vg, lv),
+                    None => String::from(entry.path().to_string_lossy())
+                };
             }
         }
         Ok(dev_path)
     }
 
-    pub fn get_dev_path(&mut self, dev: &str) -> String {
+    /// Turn a kernel name and its path into the device label, according to the label mode.
+    ///
+    /// If the device has no such identifier (e.g. by-uuid for a disk that has
+    /// partitions), we fall back to the path.
+    fn device_label(&self, name: &str, path: String) -> String {
+        let link = |kind: &str| disk_links(kind, name).into_iter().next();
+        let label = match self.options.label {
+            DeviceLabel::Path       => None,
+            DeviceLabel::ById       => link("by-id"),
+            DeviceLabel::ByPath     => link("by-path"),
+            DeviceLabel::ByUuid     => link("by-uuid"),
+            DeviceLabel::ByPartuuid => link("by-partuuid"),
+            DeviceLabel::Wwn        => disk_links("by-id", name)
+                .into_iter()
+                .find(|link| link.contains("/wwn-") || link.contains("/nvme-eui."))
+                .or_else(|| wwid(&disk_dir(name))),
+            DeviceLabel::Serial     => serial(&disk_dir(name)),
+        };
+        label.unwrap_or(path)
+    }
+
+    /// Resolve a kernel name like "sda2" or "dm-0" to its device label.
+    fn label_for_name(&self, name: &str) -> String {
+        let path = match majmin(name) {
+            Some(dev) => self.resolve(&dev).unwrap_or_else(|_| format!("/dev/{}", name)),
+            None => format!("/dev/{}", name)
+        };
+        self.device_label(name, path)
+    }
+
+    /// Map a device to the one we attribute its requests to, according to the aggregation level.
+    ///
+    /// Devices that are members of more than one stack (e.g. a PV with multiple LVs)
+    /// cannot be attributed to a single top-level device, so they stay at disk level.
+    fn aggregate(&self, dev: &str, name: &str) -> (String, String) {
+        if self.options.aggregation == Aggregation::Partition {
+            return (String::from(dev), String::from(name));
+        }
+        let disk = whole_disk(name).unwrap_or_else(|| String::from(name));
+        let target = match self.options.aggregation {
+            Aggregation::TopLevel => {
+                let mut top_levels = top_levels(&disk);
+                top_levels.sort();
+                top_levels.dedup();
+                if top_levels.len() == 1 { top_levels.remove(0) } else { disk }
+            },
+            _ => disk
+        };
+        if target == name {
+            return (String::from(dev), target);
+        }
+        match majmin(&target) {
+            Some(target_dev) => (target_dev, target),
+            None => (String::from(dev), String::from(name))
+        }
+    }
+
+    fn publish_info(&self, name: &str, label: &str, series: &mut Vec<(IntGaugeVec, Vec<String>)>) {
+        let attrs = device_attrs(name);
+        let mut set_info = |parent: &str, top_level: &str| {
+            let mut labels = vec![String::from(label), String::from(parent), String::from(top_level)];
+            labels.extend(attrs.iter().cloned());
+            set_series(series, &self.g_device_info, labels);
+        };
+        let holders = holders(name);
+        if holders.is_empty() {
+            set_info("", label);
+        }
+        for holder in holders {
+            let holder_label = self.label_for_name(&holder);
+            for top_level in top_levels(&holder) {
+                set_info(&holder_label, &self.label_for_name(&top_level));
+            }
+        }
+        if let Some((controller, nsid, head)) = nvme_info(name) {
+            let head_label = if head.is_empty() { head } else { self.label_for_name(&head) };
+            set_series(series, &self.g_nvme_info, vec![String::from(label), controller, nsid, head_label]);
+        }
+    }
+
+    /// Publish mount info for this device and its partitions, and return the mount points.
+    fn publish_mounts(&self, dev: &str, name: &str, label: &str, series: &mut Vec<(IntGaugeVec, Vec<String>)>) -> String {
+        // Requests are usually traced against the whole disk, so include its partitions
+        let mut devs = vec![(String::from(dev), String::new())];
+        for part in partitions(name) {
+            if let Some(part_dev) = majmin(&part) {
+                devs.push((part_dev, part));
+            }
+        }
+        let mut mountpoints = vec![];
+        for (dev, partition) in devs {
+            for mount in self.mounts.get(&dev) {
+                set_series(series, &self.g_mount_info, vec![
+                    String::from(label), partition.clone(), mount.mountpoint.clone(), mount.fstype.clone()
+                ]);
+                mountpoints.push(mount.mountpoint.clone());
+            }
+        }
+        mountpoints.sort();
+        mountpoints.join(",")
+    }
+
+    /// Publish the info for a device, or just take another reference if that's done already.
+    ///
+    /// Mount info is only published if `dev` is given. Returns the mount points.
+    fn acquire_info(&mut self, name: &str, label: &str, dev: Option<&str>) -> String {
+        let mut published = match self.published.remove(name) {
+            Some(mut published) => {
+                published.users += 1;
+                published
+            },
+            None => {
+                let mut series = vec![];
+                self.publish_info(name, label, &mut series);
+                Published {
+                    users: 1,
+                    label: String::from(label),
+                    series,
+                    mount_dev: None,
+                    mountpoints: String::new(),
+                    mount_series: vec![],
+                }
+            }
+        };
+        if let (Some(dev), None) = (dev, &published.mount_dev) {
+            published.mountpoints = self.publish_mounts(dev, name, label, &mut published.mount_series);
+            published.mount_dev = Some(String::from(dev));
+        }
+        let mountpoints = published.mountpoints.clone();
+        self.published.insert(String::from(name), published);
+        mountpoints
+    }
+
+    fn release_info(&mut self, name: &str) {
+        let unused = match self.published.get_mut(name) {
+            Some(published) => {
+                published.users -= 1;
+                published.users == 0
+            },
+            None => false
+        };
+        if unused {
+            if let Some(published) = self.published.remove(name) {
+                remove_series(published.series);
+                remove_series(published.mount_series);
+            }
+        }
+    }
+
+    fn reload_partitions(&mut self) {
+        if let Ok(proc_partitions) = fs::read_to_string("/proc/partitions") {
+            self.proc_partitions = proc_partitions;
+        }
+    }
+
+    fn invalidate(&mut self, dev: &str) {
+        if let Some(device) = self.cache.remove(dev) {
+            for name in device.published {
+                self.release_info(&name);
+            }
+        }
+    }
+
+    fn invalidate_all(&mut self) {
+        let devs: Vec<String> = self.cache.keys().cloned().collect();
+        for dev in devs {
+            self.invalidate(&dev);
+        }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Change how devices are reported.
+    ///
+    /// Everything we know about a device depends on the options, so this starts over
+    /// with an empty cache. Changing the label names is up to the caller, since the
+    /// metrics using them would need to be registered again.
+    pub fn set_options(&mut self, options: Options) {
+        self.options = options;
+        self.reload_partitions();
+        self.invalidate_all();
+    }
+
+    /// The uevent socket's fd, or -1 (which poll() ignores) if we don't have one.
+    pub fn uevent_fd(&self) -> RawFd {
+        self.uevents.as_ref().map(UeventSocket::fd).unwrap_or(-1)
+    }
+
+    /// Process pending uevents and look up the devices that changed again.
+    ///
+    /// Returns whether devices have been added or removed.
+    pub fn handle_uevents(&mut self) -> bool {
+        let events = match self.uevents {
+            Some(ref uevents) => uevents.read_events(),
+            None => return false
+        };
+        if events.is_empty() {
+            return false;
+        }
+        self.reload_partitions();
+        let mut added_or_removed = false;
+        let mut stale: Vec<String> = vec![];
+        for event in events {
+            log::debug("Device changed", &[("action", &event.action), ("dev", event.dev.as_deref().unwrap_or(""))]);
+            added_or_removed |= event.action == "add" || event.action == "remove";
+            // Devices being added or removed change the holders and slaves of other
+            // devices too, and a removed device's major:minor may be reused. Once it's
+            // gone, sysfs can't tell us its stack anymore, but the devices that were
+            // part of it still remember.
+            let affected = event.name.as_deref().map(stack).unwrap_or_default();
+            for (dev, device) in &self.cache {
+                if event.dev.as_ref() == Some(dev) || device.related.iter().any(|name| affected.contains(name)) {
+                    stale.push(dev.clone());
+                }
+            }
+        }
+        stale.sort();
+        stale.dedup();
+        for dev in &stale {
+            self.invalidate(dev);
+        }
+        // Rather than waiting for the next request, so that the info is up to date
+        for dev in stale {
+            if Path::new("/sys/dev/block").join(dev.replace(',', ":")).exists() {
+                self.lookup(&dev);
+            }
+        }
+        added_or_removed
+    }
+
+    /// The kernel's dev_t values for the included and excluded devices.
+    ///
+    /// Returns None for the included devices if some of them don't exist (yet),
+    /// since we can't tell the kernel to let them through.
+    pub fn filter_devts(&self) -> (Option<Vec<u64>>, Vec<u64>) {
+        let devts = |names: &[String]| -> Vec<Option<u64>> {
+            names.iter().map(|name| {
+                let dev = majmin(name)?;
+                let mut parts = dev.split(',').map(|part| part.parse::<u64>());
+                match (parts.next(), parts.next()) {
+                    // This is the kernel's internal MKDEV(), not the one from userspace
+                    (Some(Ok(major)), Some(Ok(minor))) => Some((major << 20) | minor),
+                    _ => None
+                }
+            }).collect()
+        };
+        let include: Option<Vec<u64>> = devts(&self.options.include).into_iter().collect();
+        let exclude: Vec<u64> = devts(&self.options.exclude).into_iter().flatten().collect();
+        (include, exclude)
+    }
+
+    /// The mountinfo fd, to be polled for POLLPRI.
+    pub fn mounts_fd(&self) -> RawFd {
+        self.mounts.fd()
+    }
+
+    /// Something has been mounted, unmounted or remounted.
+    ///
+    /// Nothing else about the devices changes, so we only redo the mount info
+    /// and the mountpoint labels.
+    pub fn handle_mount_change(&mut self) {
+        log::debug("Mounts changed", &[]);
+        self.mounts.reload();
+        let names: Vec<String> = self.published.keys().cloned().collect();
+        for name in names {
+            let mut published = match self.published.remove(&name) {
+                Some(published) => published,
+                None => continue
+            };
+            if let Some(ref dev) = published.mount_dev {
+                let mut mount_series = vec![];
+                published.mountpoints = self.publish_mounts(dev, &name, &published.label, &mut mount_series);
+                // Series that are still there have been set again, so just remove the others
+                let old_series = std::mem::replace(&mut published.mount_series, mount_series);
+                remove_series(old_series
+                    .into_iter()
+                    .filter(|(_, labels)| !published.mount_series.iter().any(|(_, new_labels)| new_labels == labels))
+                    .collect());
+            }
+            self.published.insert(name, published);
+        }
+        for device in self.cache.values_mut() {
+            if let Some(published) = self.published.get(&device.name) {
+                device.mountpoints = published.mountpoints.clone();
+            }
+        }
+    }
+
+    fn lookup(&mut self, dev: &str) -> &Device {
         // Dev is a "major,minor" string
-        if self.cache.contains_key(dev) {
-            self.cache.get(dev).unwrap().clone()
-        } else {
-            let path = self.resolve(dev).expect("couldn't find name for device");
-            self.cache.insert(String::from(dev), path.clone());
-            path
+        if !self.cache.contains_key(dev) {
+            if self.kernel_name(dev).is_none() {
+                // Might be a device that appeared after we started
+                self.reload_partitions();
+            }
+            let name = match self.kernel_name(dev) {
+                Some(name) => name,
+                None => {
+                    log::limited("unknown_device", log::Level::Warn, "Could not find name for device", &[("dev", dev)]);
+                    // Go by "major:minor" and don't look for it in sysfs, where an
+                    // empty name would get us the holders and partitions of every device
+                    let name = dev.replace(',', ":");
+                    self.cache.insert(String::from(dev), Device {
+                        label: name.clone(),
+                        name,
+                        mountpoints: String::new(),
+                        wanted: self.options.include.is_empty(),
+                        zoned: false,
+                        zones: None,
+                        size: 0,
+                        physical_block_size: 512,
+                        partitions: vec![],
+                        published: vec![],
+                        related: vec![],
+                    });
+                    return &self.cache[dev];
+                }
+            };
+            // Level filtering applies to the device the request was traced against,
+            // everything else to the one we attribute it to
+            let wanted = match self.options.level {
+                DeviceLevel::All      => true,
+                DeviceLevel::Leaf     => slaves(&name).is_empty(),
+                DeviceLevel::TopLevel => holders(&name).is_empty(),
+            } && (self.options.include.is_empty() || self.options.include.contains(&name))
+              && !self.options.exclude.contains(&name);
+            // Zones are addressed by the sectors of the device the request was traced against
+            let zoned = is_zoned(&name);
+            let zones = if zoned && self.options.zone_type_label {
+                match Zones::report(&name) {
+                    Ok(zones) => Some(zones),
+                    Err(err) => {
+                        log::warn("Could not get zones", &[("device", &name), ("error", &err.to_string())]);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            // Same goes for regions and alignment
+            let size = sectors_attr(&name, "size").unwrap_or(0);
+            let physical_block_size = read_attr(&disk_dir(&name), "queue/physical_block_size")
+                .and_then(|value| value.parse().ok())
+                .filter(|size| *size > 0)
+                .unwrap_or(512);
+            let partitions = if self.options.regions == Regions::Partitions {
+                partition_ranges(&name)
+            } else {
+                vec![]
+            };
+            let related = stack(&name);
+            let traced_name = name;
+            let (agg_dev, name) = self.aggregate(dev, &traced_name);
+            let path = self.resolve(&agg_dev).unwrap_or_else(|_| format!("/dev/{}", name));
+            let label = self.device_label(&name, path);
+            let mountpoints = self.acquire_info(&name, &label, Some(&agg_dev));
+            let mut published = vec![name.clone()];
+            if traced_name != name {
+                // Otherwise the properties of member disks would never be reported
+                let traced_label = self.label_for_name(&traced_name);
+                self.acquire_info(&traced_name, &traced_label, None);
+                published.push(traced_name);
+            }
+            log::debug("New device", &[("dev", dev), ("name", &name), ("label", &label), ("wanted", &wanted.to_string())]);
+            self.cache.insert(String::from(dev), Device {
+                label, name, mountpoints, wanted, zoned, zones, size, physical_block_size, partitions, published,
+                related
+            });
+        }
+        &self.cache[dev]
+    }
+
+    /// Names of the labels that identify a device in the diskio_* metrics.
+    pub fn label_names(&self) -> Vec<&'static str> {
+        let mut names = vec!["device"];
+        if self.options.kernel_name_label {
+            names.push("kernel_name");
+        }
+        if self.options.mountpoint_label {
+            names.push("mountpoint");
+        }
+        names
+    }
+
+    /// Values for the labels returned by label_names().
+    pub fn get_labels(&mut self, dev: &str) -> Vec<String> {
+        let (kernel_name_label, mountpoint_label) =
+            (self.options.kernel_name_label, self.options.mountpoint_label);
+        let device = self.lookup(dev);
+        let mut labels = vec![device.label.clone()];
+        if kernel_name_label {
+            labels.push(device.name.clone());
+        }
+        if mountpoint_label {
+            labels.push(device.mountpoints.clone());
+        }
+        labels
+    }
+
+    /// Whether this is a zoned device, whose zone management requests show up as "N" in rwbs.
+    pub fn is_zoned(&mut self, dev: &str) -> bool {
+        self.lookup(dev).zoned
+    }
+
+    /// The type of the zone that the sector belongs to (empty if we don't know).
+    pub fn zone_type(&mut self, dev: &str, sector: u64) -> &'static str {
+        match self.lookup(dev).zones {
+            Some(ref zones) => zones.zone_type(sector),
+            None => ""
+        }
+    }
+
+    /// The LBA region that the sector belongs to, according to the regions setting.
+    pub fn region(&mut self, dev: &str, sector: u64) -> String {
+        let regions = self.options.regions;
+        let device = self.lookup(dev);
+        match regions {
+            Regions::None => String::new(),
+            Regions::Count(count) => {
+                if device.size == 0 {
+                    return String::new();
+                }
+                // Sectors beyond the end would only happen if the device was resized
+                // since we looked at it, so put them into the last region
+                let region = (sector as u128 * count as u128 / device.size as u128) as u64;
+                region.min(count - 1).to_string()
+            },
+            Regions::Partitions => device.partitions
+                .iter()
+                .find(|(start, end, _)| *start <= sector && sector < *end)
+                .map(|(_, _, part)| part.clone())
+                .unwrap_or_default()
         }
     }
+
+    /// The physical block size of the device in bytes.
+    pub fn physical_block_size(&mut self, dev: &str) -> u64 {
+        self.lookup(dev).physical_block_size
+    }
+
+    /// Whether or not this device matches the configured device level.
+    pub fn is_wanted(&mut self, dev: &str) -> bool {
+        self.lookup(dev).wanted
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vg_lv(vg: &str, lv: &str) -> Option<(String, String)> {
+        Some((String::from(vg), String::from(lv)))
+    }
+
+    #[test]
+    fn lvm_names() {
+        assert_eq!(split_vg_lv("vg-lv"), vg_lv("vg", "lv"));
+        assert_eq!(split_vg_lv("vg--a-lv--b"), vg_lv("vg-a", "lv-b"));
+        assert_eq!(split_vg_lv("vg----a-lv"), vg_lv("vg--a", "lv"));
+        assert_eq!(split_vg_lv("vg-lv--"), vg_lv("vg", "lv-"));
+        assert_eq!(split_vg_lv("vg-lv-tmeta"), vg_lv("vg", "lv-tmeta"));
+    }
+
+    #[test]
+    fn not_lvm_names() {
+        assert_eq!(split_vg_lv("vg"), None);
+        assert_eq!(split_vg_lv("vg-"), None);
+        assert_eq!(split_vg_lv("vg--"), None);
+        assert_eq!(split_vg_lv("vg--a"), None);
+        assert_eq!(split_vg_lv("-lv"), None);
+        assert_eq!(split_vg_lv(""), None);
+    }
+}
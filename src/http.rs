@@ -0,0 +1,240 @@
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use prometheus::{Counter, Histogram, IntCounter, IntGauge, TextEncoder, Encoder};
+
+use super::errors::*;
+use super::log;
+use super::privs;
+use super::process;
+use super::systemd;
+use super::{print_error, TIME_HISTOGRAM_BUCKETS};
+
+// A client that takes longer than this to send its request and read our response
+// gets dropped
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Each client is served in a thread of its own, so a slow one can't hold up the
+// next scrape. Connections beyond this many are closed right away.
+const MAX_CLIENTS: usize = 4;
+
+/// Metrics about serving metrics. These outlive the server, which is replaced
+/// when the port changes.
+#[derive(Clone)]
+pub struct ScrapeMetrics {
+    c_scrapes: IntCounter,
+    h_scrape_duration: Histogram,
+    c_process_cpu: Counter,
+    g_process_rss: IntGauge,
+}
+
+/// Serves metrics in threads of its own, so that slow clients and large
+/// responses never hold up reading the trace.
+///
+/// The metrics themselves are shared through prometheus' global registry,
+/// which is safe to update from one thread while gathering in another.
+pub struct Server {
+    listener: TcpListener,
+    socket_activated: bool,
+    metrics: ScrapeMetrics,
+}
+
+/// A running server.
+pub struct Handle {
+    running: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+    pub socket_activated: bool,
+}
+
+/// Counts a client as served when it goes away, even if serving it panicked.
+struct ClientSlot(Arc<AtomicUsize>);
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Time left until the deadline, or an error if it has passed.
+fn remaining(deadline: Instant) -> Result<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        bail!("Client took too long");
+    }
+    Ok(deadline - now)
+}
+
+fn sync_float_counter(counter: &Counter, value: f64) {
+    let current = counter.get();
+    if value > current {
+        counter.inc_by(value - current);
+    }
+}
+
+impl ScrapeMetrics {
+    pub fn register() -> Result<Self> {
+        let c_scrapes = register_int_counter!(
+            "lagerist_scrapes_total",
+            "Metrics requests served"
+        ).chain_err(|| "Couldn't set up scrapes counter")?;
+
+        let h_scrape_duration = register_histogram!(
+            histogram_opts!("lagerist_scrape_duration_seconds", "Time spent serving a metrics request")
+                .buckets(TIME_HISTOGRAM_BUCKETS.iter().map(|x| x / 1000.0).collect())
+        ).chain_err(|| "Couldn't set up scrape duration histogram")?;
+
+        let c_process_cpu = register_counter!(
+            "lagerist_process_cpu_seconds_total",
+            "User and system CPU time spent by lagerist (updated on scrape)"
+        ).chain_err(|| "Couldn't set up process CPU counter")?;
+
+        let g_process_rss = register_int_gauge!(
+            "lagerist_process_resident_memory_bytes",
+            "Resident memory size of lagerist (updated on scrape)"
+        ).chain_err(|| "Couldn't set up process memory gauge")?;
+
+        Ok(Self { c_scrapes, h_scrape_duration, c_process_cpu, g_process_rss })
+    }
+}
+
+impl Server {
+    /// Listen on the given port, unless systemd has passed us a listener.
+    ///
+    /// systemd only passes it once, so when we're called again we bind the port ourselves.
+    pub fn bind(port: u16, metrics: ScrapeMetrics) -> Result<Self> {
+        let (listener, socket_activated) = match systemd::listener() {
+            Some(listener) => (listener, true),
+            None => (
+                TcpListener::bind(format!(":::{}", port)).chain_err(|| "Could not start server")?,
+                false
+            )
+        };
+        listener.set_nonblocking(true)
+            .chain_err(|| "Could not set nonblocking")?;
+        Ok(Self { listener, socket_activated, metrics })
+    }
+
+    pub fn spawn(self) -> Result<Handle> {
+        let running = Arc::new(AtomicBool::new(true));
+        let running_clone = running.clone();
+        let socket_activated = self.socket_activated;
+        let thread = thread::Builder::new()
+            .name(String::from("http"))
+            .spawn(move || self.run(&running_clone))
+            .chain_err(|| "Could not start server thread")?;
+        Ok(Handle { running, thread, socket_activated })
+    }
+
+    fn run(&self, running: &AtomicBool) {
+        // We may have been started after dropping privileges, and inherited the
+        // capabilities that the main thread kept
+        if let Err(err) = privs::drop_thread_capabilities() {
+            print_error("Not serving metrics", &err);
+            return;
+        }
+        let mut pollfd = libc::pollfd {
+            fd:      self.listener.as_raw_fd(),
+            events:  libc::POLLIN,
+            revents: 0
+        };
+        let clients = Arc::new(AtomicUsize::new(0));
+        while running.load(Ordering::SeqCst) {
+            // Wake up regularly to check whether we're supposed to stop
+            if unsafe { libc::poll(&mut pollfd, 1, 100) } <= 0 {
+                continue;
+            }
+            loop {
+                match self.listener.accept() {
+                    Ok((stream, _)) => {
+                        if clients.load(Ordering::SeqCst) >= MAX_CLIENTS {
+                            log::limited("too_many_clients", log::Level::Warn, "Too many clients, closing connection", &[]);
+                            continue;
+                        }
+                        clients.fetch_add(1, Ordering::SeqCst);
+                        let slot = ClientSlot(clients.clone());
+                        let metrics = self.metrics.clone();
+                        let spawned = thread::Builder::new()
+                            .name(String::from("http-client"))
+                            .spawn(move || {
+                                let _slot = slot;
+                                if let Err(err) = privs::drop_thread_capabilities()
+                                    .and_then(|()| handle_client(&metrics, stream)) {
+                                    print_error("Could not serve client", &err);
+                                }
+                            });
+                        if let Err(err) = spawned {
+                            print_error("Could not start client thread", &Error::with_chain(err, "spawn failed"));
+                        }
+                    }
+                    Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        print_error("Could not accept client connection", &Error::with_chain(err, "accept failed"));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn handle_client(metrics: &ScrapeMetrics, mut client: TcpStream) -> Result<()> {
+    // The listener is non-blocking, but for the client we'd rather wait, just not forever.
+    // The timeouts only apply to a single read or write, so we keep track of the total.
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+    client.set_nonblocking(false)
+        .chain_err(|| "Could not set client to blocking")?;
+    client.set_read_timeout(Some(remaining(deadline)?))
+        .chain_err(|| "Could not set client read timeout")?;
+
+    // Handle the request. We always use Connection:Close semantics because easier.
+    let mut data = [0u8; 16_384];
+    client.read(&mut data)
+        .chain_err(|| "Could not read client data")?;
+
+    // We don't really care for the request, we always respond with our data
+    let scrape_start = Instant::now();
+    metrics.c_scrapes.inc();
+    if let Some(stats) = process::ProcessStats::read() {
+        sync_float_counter(&metrics.c_process_cpu, stats.cpu_seconds);
+        metrics.g_process_rss.set(stats.resident_bytes as i64);
+    }
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    let metric_families = prometheus::gather();
+    encoder.encode(&metric_families, &mut buffer)
+        .chain_err(|| "Could not encode metrics")?;
+    let output = String::from_utf8_lossy(&buffer);
+    let response = format!(
+        "HTTP/1.1 200 OK\nContent-Type: text/plain\nContent-Length: {}\n\n{}\n",
+        output.len(),
+        output
+    );
+    let mut response = response.as_bytes();
+    while !response.is_empty() {
+        client.set_write_timeout(Some(remaining(deadline)?))
+            .chain_err(|| "Could not set client write timeout")?;
+        match client.write(response) {
+            Ok(0) => bail!("Client closed the connection"),
+            Ok(count) => response = &response[count..],
+            Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).chain_err(|| "Could not send response to client")
+        }
+    }
+    metrics.h_scrape_duration.observe(scrape_start.elapsed().as_secs_f64());
+    Ok(())
+}
+
+impl Handle {
+    /// Stop serving and close the listener.
+    pub fn stop(self) -> Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+        if self.thread.join().is_err() {
+            bail!("Server thread panicked");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+/// Timestamps of requests that are in flight, keyed by "major,minor,sector,nr_sectors".
+///
+/// Bios that get merged into a request after it has been inserted change the
+/// request's sector range, which would break the key. Thus we can keep an index of
+/// where each request starts and ends, so that we can move the entry to its new
+/// key when a block_bio_backmerge or block_bio_frontmerge event comes in.
+pub struct InFlight {
+    times: HashMap<String, f64>,
+    merges: Option<MergeIndex>,     // only if we follow merges
+}
+
+struct MergeIndex {
+    by_start: HashMap<String, u64>,   // "dev,start sector" -> nr_sectors
+    by_end:   HashMap<String, u64>,   // "dev,end sector"   -> start sector
+}
+
+pub fn key(dev: &str, sector: u64, nr_sectors: u64) -> String {
+    format!("{},{},{}", dev, sector, nr_sectors)
+}
+
+fn pos(dev: &str, sector: u64) -> String {
+    format!("{},{}", dev, sector)
+}
+
+impl MergeIndex {
+    fn insert(&mut self, dev: &str, sector: u64, nr_sectors: u64) {
+        self.by_start.insert(pos(dev, sector), nr_sectors);
+        self.by_end.insert(pos(dev, sector + nr_sectors), sector);
+    }
+
+    fn remove(&mut self, dev: &str, sector: u64, nr_sectors: u64) {
+        self.by_start.remove(&pos(dev, sector));
+        self.by_end.remove(&pos(dev, sector + nr_sectors));
+    }
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self {
+            times:  HashMap::new(),
+            merges: None,
+        }
+    }
+
+    /// Like new(), but able to follow merges.
+    pub fn with_merges() -> Self {
+        Self {
+            times:  HashMap::new(),
+            merges: Some(MergeIndex { by_start: HashMap::new(), by_end: HashMap::new() }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.times.contains_key(key)
+    }
+
+    pub fn insert(&mut self, dev: &str, sector: u64, nr_sectors: u64, time: f64) {
+        self.times.insert(key(dev, sector, nr_sectors), time);
+        if let Some(ref mut merges) = self.merges {
+            merges.insert(dev, sector, nr_sectors);
+        }
+    }
+
+    pub fn remove(&mut self, dev: &str, sector: u64, nr_sectors: u64) -> Option<f64> {
+        let time = self.times.remove(&key(dev, sector, nr_sectors))?;
+        if let Some(ref mut merges) = self.merges {
+            merges.remove(dev, sector, nr_sectors);
+        }
+        Some(time)
+    }
+
+    /// A bio starting at `sector` has been appended to the request that ends there.
+    pub fn backmerge(&mut self, dev: &str, sector: u64, nr_sectors: u64) -> bool {
+        let start = match self.merges.as_ref().and_then(|merges| merges.by_end.get(&pos(dev, sector))) {
+            Some(start) => *start,
+            None => return false
+        };
+        match self.remove(dev, start, sector - start) {
+            Some(time) => {
+                self.insert(dev, start, sector - start + nr_sectors, time);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// A bio ending at the start of a request has been prepended to it.
+    pub fn frontmerge(&mut self, dev: &str, sector: u64, nr_sectors: u64) -> bool {
+        let end = sector + nr_sectors;
+        let rq_sectors = match self.merges.as_ref().and_then(|merges| merges.by_start.get(&pos(dev, end))) {
+            Some(nr) => *nr,
+            None => return false
+        };
+        match self.remove(dev, end, rq_sectors) {
+            Some(time) => {
+                self.insert(dev, sector, nr_sectors + rq_sectors, time);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Drop all entries older than `max_age` seconds relative to `now`.
+    pub fn expire(&mut self, now: f64, max_age: f64) {
+        let merges = &mut self.merges;
+        self.times.retain(|key, time| {
+            if now < *time + max_age {
+                return true;
+            }
+            if let Some(ref mut merges) = merges {
+                // key is "major,minor,sector,nr_sectors"
+                let parts: Vec<&str> = key.rsplitn(3, ',').collect();
+                if let (Ok(nr_sectors), Ok(sector)) = (parts[0].parse::<u64>(), parts[1].parse::<u64>()) {
+                    merges.remove(parts[2], sector, nr_sectors);
+                }
+            }
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEV: &str = "8,0";
+
+    fn index_len(inflight: &InFlight) -> (usize, usize) {
+        let merges = inflight.merges.as_ref().unwrap();
+        (merges.by_start.len(), merges.by_end.len())
+    }
+
+    #[test]
+    fn backmerge_extends_the_request() {
+        let mut inflight = InFlight::with_merges();
+        inflight.insert(DEV, 100, 8, 1.0);
+        assert!(inflight.backmerge(DEV, 108, 8));
+        assert!(!inflight.contains_key(&key(DEV, 100, 8)));
+        assert_eq!(inflight.remove(DEV, 100, 16), Some(1.0));
+        assert_eq!(inflight.len(), 0);
+        assert_eq!(index_len(&inflight), (0, 0));
+    }
+
+    #[test]
+    fn frontmerge_moves_the_start() {
+        let mut inflight = InFlight::with_merges();
+        inflight.insert(DEV, 100, 8, 1.0);
+        assert!(inflight.frontmerge(DEV, 92, 8));
+        assert!(!inflight.contains_key(&key(DEV, 100, 8)));
+        assert_eq!(inflight.remove(DEV, 92, 16), Some(1.0));
+        assert_eq!(index_len(&inflight), (0, 0));
+    }
+
+    #[test]
+    fn chained_merges() {
+        let mut inflight = InFlight::with_merges();
+        inflight.insert(DEV, 100, 8, 1.0);
+        inflight.insert(DEV, 200, 8, 2.0);
+        assert!(inflight.backmerge(DEV, 108, 8));
+        assert!(inflight.backmerge(DEV, 116, 4));
+        assert!(inflight.frontmerge(DEV, 96, 4));
+        assert!(inflight.frontmerge(DEV, 88, 8));
+        // The old ends and starts must not match anymore
+        assert!(!inflight.backmerge(DEV, 108, 8));
+        assert!(!inflight.frontmerge(DEV, 92, 8));
+        assert_eq!(inflight.len(), 2);
+        assert_eq!(inflight.remove(DEV, 88, 32), Some(1.0));
+        assert_eq!(inflight.remove(DEV, 200, 8), Some(2.0));
+        assert_eq!(index_len(&inflight), (0, 0));
+    }
+
+    #[test]
+    fn merges_stay_on_their_device() {
+        let mut inflight = InFlight::with_merges();
+        inflight.insert(DEV, 100, 8, 1.0);
+        assert!(!inflight.backmerge("8,16", 108, 8));
+        assert!(!inflight.frontmerge("8,16", 92, 8));
+        assert!(inflight.contains_key(&key(DEV, 100, 8)));
+    }
+
+    #[test]
+    fn merges_need_the_index() {
+        let mut inflight = InFlight::new();
+        inflight.insert(DEV, 100, 8, 1.0);
+        assert!(!inflight.backmerge(DEV, 108, 8));
+        assert!(!inflight.frontmerge(DEV, 92, 8));
+        assert_eq!(inflight.remove(DEV, 100, 8), Some(1.0));
+    }
+
+    #[test]
+    fn expire_cleans_up_the_index() {
+        let mut inflight = InFlight::with_merges();
+        inflight.insert(DEV, 100, 8, 1.0);
+        inflight.insert(DEV, 200, 8, 500.0);
+        assert!(inflight.backmerge(DEV, 108, 8));
+        inflight.expire(700.0, 600.0);
+        assert_eq!(inflight.len(), 1);
+        assert_eq!(index_len(&inflight), (1, 1));
+        assert!(!inflight.backmerge(DEV, 116, 8));
+        assert!(inflight.backmerge(DEV, 208, 8));
+        inflight.expire(1200.0, 600.0);
+        assert_eq!(inflight.len(), 0);
+        assert_eq!(index_len(&inflight), (0, 0));
+    }
+}
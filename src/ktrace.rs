@@ -1,56 +1,442 @@
-use std::fs::{create_dir,remove_dir,OpenOptions};
+use std::fs::{self,create_dir,remove_dir,File,OpenOptions};
 use std::io::prelude::*;
-use super::errors::{Result, ResultExt};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::ffi::CString;
+use std::path::Path;
+use super::errors::{ErrorKind, Result, ResultExt};
+use super::mounts;
+use super::log;
+
+// Where tracefs lives on modern kernels. Older ones only have it below debugfs.
+const TRACEFS_PATH: &str = "/sys/kernel/tracing";
+const DEBUGFS_TRACING_PATH: &str = "/sys/kernel/debug/tracing";
 
 fn echo_into(value: &[u8], path: &str) -> Result<()> {
     let mut file = OpenOptions::new()
         .write(true)
-        .open(&path)
+        .open(path)
         .chain_err(|| format!("could not open {}", &path))?;
     file.write_all(value)
         .chain_err(|| format!("could not write data to {}", &path))?;
     Ok(())
 }
 
+// Events we always need for request latency
+pub const RQ_EVENTS: [&str; 3] = [
+    "block_rq_issue", "block_rq_insert", "block_rq_complete"
+];
+
+// Optional collectors and the events they need
+pub const COLLECTORS: [(&str, &[&str]); 5] = [
+    ("merges",   &["block_bio_queue", "block_bio_backmerge", "block_bio_frontmerge"]),
+    ("splits",   &["block_split"]),
+    ("requeues", &["block_rq_requeue"]),
+    ("bios",     &["block_bio_queue", "block_bio_complete"]),
+    ("plugs",    &["block_plug", "block_unplug"]),
+];
+
+// Events that don't have dev and rwbs fields, so we can't filter them
+const UNFILTERABLE_EVENTS: [&str; 2] = ["block_plug", "block_unplug"];
 
-pub fn setup() -> Result<()> {
-    // Basically, do the equivalent of:
-    // INST="/sys/kernel/debug/tracing/instances/lagerist"
-    // mkdir -p "$INST"
-    // echo 1 > "$INST/events/block/block_rq_issue/enable"
-    // echo 1 > "$INST/events/block/block_rq_insert/enable"
-    // echo 1 > "$INST/events/block/block_rq_complete/enable"
-    // echo 1 > "$INST/tracing_on"
-    let instance_path = format!("/sys/kernel/debug/tracing/instances/{}", env!("CARGO_PKG_NAME"));
-    create_dir(&instance_path)
-        .or_else(
-            |err| if err.kind() == std::io::ErrorKind::AlreadyExists {
-                println!("ktrace instance already exists, using existing one");
-                Ok(())
-            } else {
-                Err(err)
+/// The events needed for the given collectors, including those we always need.
+pub fn events_for(collectors: &[&str]) -> Result<Vec<&'static str>> {
+    let mut events = RQ_EVENTS.to_vec();
+    for collector in collectors {
+        let collector_events = match COLLECTORS.iter().find(|(name, _)| name == collector) {
+            Some((_, collector_events)) => collector_events,
+            None => bail!("unknown collector: {}", collector)
+        };
+        for event in collector_events.iter() {
+            if !events.contains(event) {
+                events.push(event);
             }
-        )
-        .chain_err(|| "could not create ktrace instance")?;
-    echo_into(b"1", &format!("{}/events/block/block_rq_issue/enable",    &instance_path))?;
-    echo_into(b"1", &format!("{}/events/block/block_rq_insert/enable",   &instance_path))?;
-    echo_into(b"1", &format!("{}/events/block/block_rq_complete/enable", &instance_path))?;
-    echo_into(b"1", &format!("{}/tracing_on", &instance_path))?;
-    Ok(())
+        }
+    }
+    Ok(events)
+}
+
+fn known_events() -> impl Iterator<Item=&'static &'static str> {
+    RQ_EVENTS.iter().chain(
+        COLLECTORS.iter().flat_map(|(_, collector_events)| collector_events.iter())
+    )
 }
 
-pub fn socket_path() -> String {
-    format!("/sys/kernel/debug/tracing/instances/{}/trace_pipe", env!("CARGO_PKG_NAME"))
+/// Whether this is one of the events we know how to handle.
+pub fn is_known_event(event: &str) -> bool {
+    known_events().any(|known| *known == event)
 }
 
-pub fn teardown() -> Result<()> {
-    // Basically, do the equivalent of:
-    // INST="/sys/kernel/debug/tracing/instances/lagerist"
-    // echo 0 > "$INST/tracing_on"
-    // rmdir "$INST"
-    let instance_path = format!("/sys/kernel/debug/tracing/instances/{}", env!("CARGO_PKG_NAME"));
-    echo_into(b"0", &format!("{}/tracing_on", &instance_path))?;
-    remove_dir(&instance_path)
-        .chain_err(|| "could not remove ktrace instance")?;
+/// Build an ftrace filter expression that lets through only the requests we're interested in.
+///
+/// `include` and `exclude` are kernel dev_t values. An empty `include` means all devices.
+pub fn build_filter(include: &[u64], exclude: &[u64]) -> String {
+    // We only care for reads, writes and zone management (N) requests,
+    // and 0,0 comes up for requests that aren't tied to a device
+    let mut filter = String::from(r#"dev != 0 && (rwbs ~ "*R*" || rwbs ~ "*W*" || rwbs ~ "*N*")"#);
+    if !include.is_empty() {
+        let include: Vec<String> = include.iter().map(|devt| format!("dev == {}", devt)).collect();
+        filter.push_str(&format!(" && ({})", include.join(" || ")));
+    }
+    for devt in exclude {
+        filter.push_str(&format!(" && dev != {}", devt));
+    }
+    filter
+}
+
+/// Explain why we can't get at tracefs, if we can tell.
+fn diagnose_tracefs() -> String {
+    // With lockdown in confidentiality mode, tracefs is off limits even for root
+    if let Ok(lockdown) = fs::read_to_string("/sys/kernel/security/lockdown") {
+        if lockdown.contains("[confidentiality]") {
+            return String::from("kernel lockdown is in confidentiality mode, which disables tracefs");
+        }
+    }
+    if !Path::new(TRACEFS_PATH).exists() && !Path::new("/sys/kernel/debug").exists() {
+        return String::from("the kernel seems to be built without tracing support (CONFIG_FTRACE)");
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        return String::from("not running as root, and tracefs needs root (or CAP_SYS_ADMIN) to mount, or permissions granted to our group to use");
+    }
+    String::from("mount it using: mount -t tracefs nodev /sys/kernel/tracing, or pass --mount-tracefs")
+}
+
+fn mount_tracefs() -> Result<()> {
+    let target = CString::new(TRACEFS_PATH).unwrap();
+    let fstype = CString::new("tracefs").unwrap();
+    let source = CString::new("nodev").unwrap();
+    let result = unsafe {
+        libc::mount(source.as_ptr(), target.as_ptr(), fstype.as_ptr(), 0, std::ptr::null())
+    };
+    if result == -1 {
+        let err = std::io::Error::last_os_error();
+        let reason = match err.raw_os_error() {
+            Some(libc::ENODEV) => String::from("the kernel does not support tracefs (CONFIG_FTRACE)"),
+            Some(libc::ENOENT) => format!("{} does not exist, the kernel seems to be built without tracing support", TRACEFS_PATH),
+            _ => diagnose_tracefs()
+        };
+        return Err(err).chain_err(|| ErrorKind::TracefsUnavailable(
+            format!("could not mount it on {}: {}", TRACEFS_PATH, reason)
+        ));
+    }
     Ok(())
 }
+
+/// Find out where tracefs is mounted, and optionally mount it if it isn't.
+pub fn find_tracefs(mount: bool) -> Result<String> {
+    let mountpoints = mounts::find_by_fstype("tracefs");
+    if mountpoints.iter().any(|path| path == TRACEFS_PATH) {
+        return Ok(String::from(TRACEFS_PATH));
+    }
+    if let Some(path) = mountpoints.into_iter().next() {
+        return Ok(path);
+    }
+    // debugfs automounts tracefs on first access, so it won't be in mountinfo yet
+    if Path::new(DEBUGFS_TRACING_PATH).join("instances").is_dir() {
+        return Ok(String::from(DEBUGFS_TRACING_PATH));
+    }
+    if mount {
+        mount_tracefs()?;
+        log::info("Mounted tracefs", &[("path", TRACEFS_PATH)]);
+        return Ok(String::from(TRACEFS_PATH));
+    }
+    bail!(ErrorKind::TracefsUnavailable(format!("it is not mounted; {}", diagnose_tracefs())));
+}
+
+/// Find the PID of the process holding a lock on the given inode, from /proc/locks.
+fn lock_owner(inode: u64) -> Option<String> {
+    // 1: FLOCK  ADVISORY  WRITE 1234 00:0c:5678 0 EOF
+    let locks = fs::read_to_string("/proc/locks").ok()?;
+    locks.lines()
+        .map(|line| line.split_ascii_whitespace().collect::<Vec<&str>>())
+        .find(|fields| fields.len() > 5 && fields[5].rsplit(':').next() == Some(&inode.to_string()))
+        .map(|fields| String::from(fields[4]))
+}
+
+/// Ring buffer stats of a single CPU.
+pub struct CpuStats {
+    pub cpu: String,
+    pub entries: u64,           // events currently in the buffer
+    pub overrun: u64,           // events overwritten because the buffer was full
+    pub commit_overrun: u64,    // events lost because of nested writers wrapping the buffer
+    pub dropped_events: u64,    // events discarded because the buffer was full (overwrite off)
+}
+
+impl CpuStats {
+    fn parse(cpu: &str, contents: &str) -> Self {
+        // entries: 42
+        // overrun: 0
+        // commit overrun: 0
+        // bytes: 3560
+        // ...
+        // dropped events: 0
+        let mut stats = Self {
+            cpu: String::from(cpu),
+            entries: 0,
+            overrun: 0,
+            commit_overrun: 0,
+            dropped_events: 0,
+        };
+        for line in contents.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key, value.trim().parse::<u64>().unwrap_or(0)),
+                None => continue
+            };
+            match key {
+                "entries" => stats.entries = value,
+                "overrun" => stats.overrun = value,
+                "commit overrun" => stats.commit_overrun = value,
+                "dropped events" => stats.dropped_events = value,
+                _ => ()
+            }
+        }
+        stats
+    }
+}
+
+/// An ftrace instance that we own.
+///
+/// We hold an exclusive flock() on the instance directory for as long as we're
+/// using it. tracefs does not let us create a PID file inside the instance, but
+/// the lock works across containers just as well, and the kernel drops it when
+/// we die, so a locked instance has a live owner and an unlocked one is stale.
+///
+/// When running without root, the instance may have been created for us with
+/// permissions we can use, and we're not allowed to remove it. Then we reuse it.
+pub struct Instance {
+    path: String,
+    _lock: File,
+    owned: bool,    // whether we created it and remove it when we're done
+}
+
+impl Instance {
+    pub fn setup(tracefs: &str, name: &str, events: &[&str], buffer_size_kb: Option<u64>) -> Result<Self> {
+        // Basically, do the equivalent of:
+        // INST="/sys/kernel/tracing/instances/lagerist"
+        // mkdir -p "$INST"
+        // echo $buffer_size_kb > "$INST/buffer_size_kb"
+        // for event in $events; do
+        //     echo 1 > "$INST/events/block/$event/enable"
+        // done
+        // echo 1 > "$INST/tracing_on"
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            bail!("invalid ktrace instance name: {:?}", name);
+        }
+        let path = format!("{}/instances/{}", tracefs, name);
+        // Held until we're done replacing a stale instance, so that another lagerist
+        // starting at the same time can't take it over in between
+        let _instances_lock = Self::lock_instances(&format!("{}/instances", tracefs))?;
+        let existed = match create_dir(&path) {
+            Ok(()) => false,
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => true,
+            Err(ref err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                let reason = diagnose_tracefs();
+                return Err(std::io::Error::from(err.kind())).chain_err(|| ErrorKind::TracefsUnavailable(
+                    format!("could not create ktrace instance {}: {}", &path, reason)
+                ));
+            },
+            Err(err) => return Err(err).chain_err(|| "could not create ktrace instance")
+        };
+        let mut lock = Self::lock(&path)?;
+        let mut owned = true;
+        if existed {
+            // Left behind by a process that crashed or was killed. Remove and
+            // recreate it, so we don't inherit its events, filters and buffer contents.
+            drop(lock);
+            match remove_dir(&path) {
+                Ok(()) => {
+                    log::warn("Removed stale ktrace instance", &[("instance", &path)]);
+                    create_dir(&path)
+                        .chain_err(|| "could not create ktrace instance")?;
+                },
+                Err(ref err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                    log::info("Reusing ktrace instance we may not remove", &[("instance", &path)]);
+                    owned = false;
+                },
+                Err(err) => return Err(err)
+                    .chain_err(|| format!("could not remove stale ktrace instance {}", &path))
+            }
+            lock = Self::lock(&path)?;
+        }
+        let instance = Self { path, _lock: lock, owned };
+        if !owned {
+            // Reset whatever the last user left behind
+            instance.set_events(&[])?;
+            instance.set_filter("0")?;
+            OpenOptions::new().write(true).truncate(true).open(format!("{}/trace", &instance.path))
+                .chain_err(|| "could not clear the ring buffer")?;
+        }
+        if let Some(buffer_size_kb) = buffer_size_kb {
+            instance.set_buffer_size(buffer_size_kb)?;
+        }
+        for event in events {
+            echo_into(b"1", &format!("{}/events/block/{}/enable", &instance.path, event))?;
+        }
+        echo_into(b"1", &format!("{}/tracing_on", &instance.path))?;
+        Ok(instance)
+    }
+
+    /// Wait for other lagerists to finish creating or removing their instances.
+    fn lock_instances(path: &str) -> Result<File> {
+        let dir = match File::open(path) {
+            Ok(dir) => dir,
+            Err(ref err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                let reason = diagnose_tracefs();
+                return Err(std::io::Error::from(err.kind())).chain_err(|| ErrorKind::TracefsUnavailable(
+                    format!("could not open {}: {}", path, reason)
+                ));
+            },
+            Err(err) => return Err(err).chain_err(|| format!("could not open {}", path))
+        };
+        if unsafe { libc::flock(dir.as_raw_fd(), libc::LOCK_EX) } == -1 {
+            return Err(std::io::Error::last_os_error()).chain_err(|| format!("could not lock {}", path));
+        }
+        Ok(dir)
+    }
+
+    fn lock(path: &str) -> Result<File> {
+        let dir = File::open(path)
+            .chain_err(|| format!("could not open ktrace instance {}", path))?;
+        if unsafe { libc::flock(dir.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::WouldBlock {
+                let owner = dir.metadata().ok()
+                    .and_then(|meta| lock_owner(meta.ino()))
+                    .map(|pid| format!(" (PID {})", pid))
+                    .unwrap_or_default();
+                bail!(ErrorKind::InstanceInUse(String::from(path), owner));
+            }
+            return Err(err).chain_err(|| format!("could not lock ktrace instance {}", path));
+        }
+        Ok(dir)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn socket_path(&self) -> String {
+        format!("{}/trace_pipe", &self.path)
+    }
+
+    /// Resize the ring buffer of each CPU. This can be done while tracing is on.
+    pub fn set_buffer_size(&self, buffer_size_kb: u64) -> Result<()> {
+        echo_into(buffer_size_kb.to_string().as_bytes(), &format!("{}/buffer_size_kb", &self.path))
+    }
+
+    /// Enable the given events in our instance, and disable all other events we know about.
+    ///
+    /// This can be done while tracing is on, so we don't lose any state.
+    pub fn set_events(&self, events: &[&str]) -> Result<()> {
+        for event in known_events() {
+            let enable: &[u8] = if events.contains(event) { b"1" } else { b"0" };
+            echo_into(enable, &format!("{}/events/block/{}/enable", &self.path, event))?;
+        }
+        Ok(())
+    }
+
+    /// Set the filter for all events we know about, so the kernel drops what we'd skip anyway.
+    pub fn set_filter(&self, filter: &str) -> Result<()> {
+        for event in known_events() {
+            if UNFILTERABLE_EVENTS.contains(event) {
+                continue;
+            }
+            echo_into(filter.as_bytes(), &format!("{}/events/block/{}/filter", &self.path, event))?;
+        }
+        Ok(())
+    }
+
+    /// Ring buffer stats for each CPU, from per_cpu/cpuN/stats.
+    pub fn cpu_stats(&self) -> Result<Vec<CpuStats>> {
+        let per_cpu = format!("{}/per_cpu", &self.path);
+        let mut stats = vec![];
+        for entry in fs::read_dir(&per_cpu).chain_err(|| format!("could not read {}", &per_cpu))? {
+            let entry = entry.chain_err(|| format!("could not read {}", &per_cpu))?;
+            let dirname = entry.file_name().to_string_lossy().into_owned();
+            if !dirname.starts_with("cpu") {
+                continue;
+            }
+            let path = entry.path().join("stats");
+            let contents = fs::read_to_string(&path)
+                .chain_err(|| format!("could not read {}", path.display()))?;
+            stats.push(CpuStats::parse(&dirname["cpu".len()..], &contents));
+        }
+        Ok(stats)
+    }
+
+    pub fn teardown(self) -> Result<()> {
+        // Basically, do the equivalent of:
+        // INST="/sys/kernel/tracing/instances/lagerist"
+        // echo 0 > "$INST/tracing_on"
+        // rmdir "$INST"
+        echo_into(b"0", &format!("{}/tracing_on", &self.path))?;
+        if !self.owned {
+            // Leave it as clean as we'd like to find it
+            self.set_events(&[])?;
+            return self.set_filter("0");
+        }
+        // Release the lock only after the instance is gone, so nobody can grab it in between
+        let instances = Path::new(&self.path).parent().map(|dir| String::from(dir.to_string_lossy())).unwrap_or_default();
+        let _instances_lock = Self::lock_instances(&instances)?;
+        remove_dir(&self.path)
+            .chain_err(|| "could not remove ktrace instance")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTER: &str = r#"dev != 0 && (rwbs ~ "*R*" || rwbs ~ "*W*" || rwbs ~ "*N*")"#;
+
+    #[test]
+    fn filter_without_devices() {
+        assert_eq!(build_filter(&[], &[]), FILTER);
+    }
+
+    #[test]
+    fn filter_with_devices() {
+        assert_eq!(build_filter(&[8388608], &[]), format!("{} && (dev == 8388608)", FILTER));
+        assert_eq!(
+            build_filter(&[8388608, 8388624], &[]),
+            format!("{} && (dev == 8388608 || dev == 8388624)", FILTER)
+        );
+        assert_eq!(
+            build_filter(&[], &[265289728, 7340032]),
+            format!("{} && dev != 265289728 && dev != 7340032", FILTER)
+        );
+        assert_eq!(
+            build_filter(&[8388608], &[8388609]),
+            format!("{} && (dev == 8388608) && dev != 8388609", FILTER)
+        );
+    }
+
+    #[test]
+    fn cpu_stats() {
+        let stats = CpuStats::parse("3", "\
+entries: 42
+overrun: 7
+commit overrun: 1
+bytes: 3560
+oldest event ts: 25071.123456
+now ts: 25080.654321
+dropped events: 12
+read events: 1000
+");
+        assert_eq!(stats.cpu, "3");
+        assert_eq!(
+            (stats.entries, stats.overrun, stats.commit_overrun, stats.dropped_events),
+            (42, 7, 1, 12)
+        );
+    }
+
+    #[test]
+    fn cpu_stats_missing_or_broken() {
+        let stats = CpuStats::parse("0", "entries: many\noverrun\nbytes: 0\n");
+        assert_eq!(
+            (stats.entries, stats.overrun, stats.commit_overrun, stats.dropped_events),
+            (0, 0, 0, 0)
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::io::{self, Read};
+
+/// Reads lines from a non-blocking source into a bounded buffer.
+///
+/// Reads can end anywhere within a line, so the partial line at the end of the
+/// buffer is kept until the rest of it comes in. The buffer never grows: once it
+/// is full, we stop reading and leave the data in the kernel until the caller has
+/// processed what we have. A single line that doesn't fit into the buffer is dropped.
+pub struct LineReader {
+    buf: Vec<u8>,
+    len: usize,         // bytes of buf in use
+    discarding: bool,   // dropping the rest of a line that was too long
+    overlong: u64,      // lines dropped because they were too long
+}
+
+impl LineReader {
+    pub fn new(size: usize) -> Self {
+        Self {
+            buf: vec![0u8; size],
+            len: 0,
+            discarding: false,
+            overlong: 0,
+        }
+    }
+
+    /// Read until the source has nothing more for us or the buffer is full.
+    ///
+    /// Returns the number of bytes read.
+    pub fn fill<R: Read>(&mut self, source: &mut R) -> io::Result<usize> {
+        let mut bytes_read = 0;
+        while self.len < self.buf.len() {
+            match source.read(&mut self.buf[self.len..]) {
+                Ok(0) => break,
+                Ok(count) => {
+                    self.len += count;
+                    bytes_read += count;
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock  => break,
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(bytes_read)
+    }
+
+    /// Whether the buffer is full, so the source may have more data for us.
+    pub fn is_full(&self) -> bool {
+        self.len == self.buf.len()
+    }
+
+    /// Take all complete lines out of the buffer, keeping a partial line at the end.
+    pub fn take_lines(&mut self) -> String {
+        let end = match self.buf[..self.len].iter().rposition(|byte| *byte == b'\n') {
+            Some(pos) => pos + 1,
+            None => {
+                if self.is_full() {
+                    // No newline in sight and no room to wait for one
+                    if !self.discarding {
+                        self.overlong += 1;
+                        self.discarding = true;
+                    }
+                    self.len = 0;
+                }
+                return String::new();
+            }
+        };
+        let mut start = 0;
+        if self.discarding {
+            // The first line is the tail of one we've already dropped
+            start = self.buf[..end].iter().position(|byte| *byte == b'\n').unwrap() + 1;
+            self.discarding = false;
+        }
+        let lines = String::from_utf8_lossy(&self.buf[start..end]).into_owned();
+        self.buf.copy_within(end..self.len, 0);
+        self.len -= end;
+        lines
+    }
+
+    /// Number of lines dropped so far because they did not fit into the buffer.
+    pub fn overlong_lines(&self) -> u64 {
+        self.overlong
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = include_str!("../disk_trace.txt");
+
+    /// Hands out data in chunks of a fixed size, and pretends to run dry every few reads.
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        chunk_size: usize,
+        reads: usize,
+    }
+
+    impl<'a> Read for ChunkedReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            if self.reads.is_multiple_of(3) {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            let count = self.chunk_size.min(buf.len()).min(self.data.len());
+            buf[..count].copy_from_slice(&self.data[..count]);
+            self.data = &self.data[count..];
+            Ok(count)
+        }
+    }
+
+    fn read_all(data: &str, chunk_size: usize, buf_size: usize) -> (Vec<String>, u64) {
+        let mut source = ChunkedReader { data: data.as_bytes(), chunk_size, reads: 0 };
+        let mut reader = LineReader::new(buf_size);
+        let mut lines = vec![];
+        while !source.data.is_empty() || reader.is_full() {
+            reader.fill(&mut source).unwrap();
+            lines.extend(reader.take_lines().lines().map(String::from));
+        }
+        (lines, reader.overlong_lines())
+    }
+
+    #[test]
+    fn chunk_size_does_not_matter() {
+        let expected: Vec<String> = TRACE.lines().map(String::from).collect();
+        for chunk_size in &[1, 2, 3, 7, 13, 64, 100, 4096, TRACE.len()] {
+            for buf_size in &[128, 1000, 1024 * 1024] {
+                let (lines, overlong) = read_all(TRACE, *chunk_size, *buf_size);
+                assert_eq!(lines, expected, "chunk size {}, buffer size {}", chunk_size, buf_size);
+                assert_eq!(overlong, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn partial_line_is_kept() {
+        let mut reader = LineReader::new(1024);
+        reader.fill(&mut "first line\nsecond ".as_bytes()).unwrap();
+        assert_eq!(reader.take_lines(), "first line\n");
+        reader.fill(&mut "line\n".as_bytes()).unwrap();
+        assert_eq!(reader.take_lines(), "second line\n");
+        assert_eq!(reader.take_lines(), "");
+    }
+
+    #[test]
+    fn overlong_lines_are_dropped() {
+        let data = format!("short\n{}\nalso short\n", "x".repeat(50));
+        for chunk_size in &[1, 5, 16, 100] {
+            let (lines, overlong) = read_all(&data, *chunk_size, 16);
+            assert_eq!(lines, vec!["short", "also short"], "chunk size {}", chunk_size);
+            assert_eq!(overlong, 1);
+        }
+    }
+}
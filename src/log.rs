@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::systemd;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn from_str(level: &str) -> Option<Self> {
+        match level {
+            "error" => Some(Level::Error),
+            "warn"  => Some(Level::Warn),
+            "info"  => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn  => "warn",
+            Level::Info  => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    // syslog(3) priorities, as used by the journal
+    fn priority(self) -> &'static str {
+        match self {
+            Level::Error => "3",
+            Level::Warn  => "4",
+            Level::Info  => "6",
+            Level::Debug | Level::Trace => "7",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Logfmt,
+    Json,
+    Journal,
+}
+
+impl Format {
+    /// "auto" logs to the journal if stderr goes there anyway, and plain text otherwise.
+    pub fn from_str(format: &str) -> Option<Self> {
+        match format {
+            "auto"    => Some(if systemd::journal_connected() { Format::Journal } else { Format::Text }),
+            "text"    => Some(Format::Text),
+            "logfmt"  => Some(Format::Logfmt),
+            "json"    => Some(Format::Json),
+            "journal" => Some(Format::Journal),
+            _ => None
+        }
+    }
+}
+
+struct Logger {
+    level: Level,
+    format: Format,
+}
+
+static LOGGER: RwLock<Logger> = RwLock::new(Logger { level: Level::Info, format: Format::Text });
+
+struct RateLimit {
+    window_start: Instant,
+    logged: u32,        // messages logged in the current window
+    suppressed: u64,    // messages suppressed since the last one we logged
+}
+
+static RATE_LIMITS: Mutex<Option<HashMap<&'static str, RateLimit>>> = Mutex::new(None);
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+const RATE_LIMIT_BURST: u32 = 10;
+
+/// Set the level and format. Until this is called, we log at info level as text.
+///
+/// This can be called again to change them.
+pub fn init(level: Level, format: Format) {
+    *LOGGER.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Logger { level, format };
+}
+
+fn logger() -> (Level, Format) {
+    let logger = LOGGER.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+    (logger.level, logger.format)
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= logger().0
+}
+
+fn quote_logfmt(value: &str) -> String {
+    if !value.is_empty() && !value.contains(|c: char| c == ' ' || c == '=' || c == '"' || c.is_control()) {
+        return String::from(value);
+    }
+    format!("{:?}", value)
+}
+
+fn quote_json(value: &str) -> String {
+    let mut result = String::from("\"");
+    for chr in value.chars() {
+        match chr {
+            '"'  => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            chr if chr.is_control() => result.push_str(&format!("\\u{:04x}", chr as u32)),
+            chr => result.push(chr)
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+}
+
+fn write(level: Level, msg: &str, fields: &[(&str, &str)]) {
+    match logger().1 {
+        Format::Text => {
+            let mut line = format!("{}: {}", level.name(), msg);
+            for (key, value) in fields {
+                line.push_str(&format!(" {}={}", key, quote_logfmt(value)));
+            }
+            eprintln!("{}", line);
+        },
+        Format::Logfmt => {
+            let mut line = format!("ts={} level={} msg={}", timestamp(), level.name(), quote_logfmt(msg));
+            for (key, value) in fields {
+                line.push_str(&format!(" {}={}", key, quote_logfmt(value)));
+            }
+            eprintln!("{}", line);
+        },
+        Format::Json => {
+            let mut line = format!(
+                "{{\"ts\":{},\"level\":\"{}\",\"msg\":{}",
+                timestamp(), level.name(), quote_json(msg)
+            );
+            for (key, value) in fields {
+                line.push_str(&format!(",{}:{}", quote_json(key), quote_json(value)));
+            }
+            line.push('}');
+            eprintln!("{}", line);
+        },
+        Format::Journal => {
+            // Journal field names must be upper case
+            let keys: Vec<String> = fields.iter().map(|(key, _)| key.to_uppercase()).collect();
+            let mut entry = vec![
+                ("MESSAGE", msg),
+                ("PRIORITY", level.priority()),
+                ("SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME")),
+            ];
+            entry.extend(keys.iter().map(String::as_str).zip(fields.iter().map(|(_, value)| *value)));
+            if !systemd::journal_send(&entry) {
+                eprintln!("{}: {}", level.name(), msg);
+            }
+        }
+    }
+}
+
+pub fn log(level: Level, msg: &str, fields: &[(&str, &str)]) {
+    if enabled(level) {
+        write(level, msg, fields);
+    }
+}
+
+/// Log a message that may come up for every trace line, at most a few times a minute.
+///
+/// Messages with the same key share a limit. Once the limit is lifted again, the
+/// next message says how many were suppressed.
+pub fn limited(key: &'static str, level: Level, msg: &str, fields: &[(&str, &str)]) {
+    if !enabled(level) {
+        return;
+    }
+    let suppressed = {
+        let mut limits = RATE_LIMITS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let limit = limits
+            .get_or_insert_with(HashMap::new)
+            .entry(key)
+            .or_insert_with(|| RateLimit { window_start: Instant::now(), logged: 0, suppressed: 0 });
+        if limit.window_start.elapsed() >= RATE_LIMIT_WINDOW {
+            limit.window_start = Instant::now();
+            limit.logged = 0;
+        }
+        if limit.logged >= RATE_LIMIT_BURST {
+            limit.suppressed += 1;
+            return;
+        }
+        limit.logged += 1;
+        std::mem::replace(&mut limit.suppressed, 0)
+    };
+    if suppressed > 0 {
+        let suppressed = suppressed.to_string();
+        let mut fields = fields.to_vec();
+        fields.push(("suppressed", &suppressed));
+        write(level, msg, &fields);
+    } else {
+        write(level, msg, fields);
+    }
+}
+
+pub fn error(msg: &str, fields: &[(&str, &str)]) {
+    log(Level::Error, msg, fields);
+}
+
+pub fn warn(msg: &str, fields: &[(&str, &str)]) {
+    log(Level::Warn, msg, fields);
+}
+
+pub fn info(msg: &str, fields: &[(&str, &str)]) {
+    log(Level::Info, msg, fields);
+}
+
+pub fn debug(msg: &str, fields: &[(&str, &str)]) {
+    log(Level::Debug, msg, fields);
+}
@@ -6,40 +6,97 @@ extern crate libc;
 #[macro_use]
 extern crate prometheus;
 
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
-use std::io::prelude::*;
-use std::ffi::CString;
-use std::net::TcpListener;
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
-use prometheus::{TextEncoder, Encoder};
+use std::time::{Duration, Instant};
+use prometheus::{IntCounter, IntCounterVec};
 
 use clap::{Arg, App};
 
 mod ktrace;
 mod dev;
+mod inflight;
+mod uevent;
+mod mounts;
+mod zoned;
+mod pattern;
+mod linereader;
+mod traceline;
+mod process;
+mod http;
+mod systemd;
+mod log;
+mod privs;
 
 mod errors {
-    error_chain! { }
+    error_chain! {
+        errors {
+            TracefsUnavailable(reason: String) {
+                description("tracefs is not available")
+                display("tracefs is not available: {}", reason)
+            }
+            InstanceInUse(path: String, owner: String) {
+                description("ktrace instance is in use")
+                display("ktrace instance {} is in use by another lagerist process{}; \
+                         use --instance to choose a different name", path, owner)
+            }
+            TracePipe(path: String) {
+                description("could not read the trace pipe")
+                display("could not read the trace pipe {}", path)
+            }
+        }
+    }
 }
 
 use errors::*;
 
 fn print_error(msg: &str, e: &Error) {
-    eprintln!("{}: {}", msg, e);
+    let error = e.to_string();
+    let causes: Vec<String> = e.iter().skip(1).map(|cause| cause.to_string()).collect();
+    let causes = causes.join("; ");
+    let backtrace = e.backtrace().map(|backtrace| format!("{:?}", backtrace));
 
-    for e in e.iter().skip(1) {
-        eprintln!("caused by: {}", e);
+    let mut fields = vec![("error", error.as_str())];
+    if !causes.is_empty() {
+        fields.push(("caused_by", causes.as_str()));
     }
-
-    if let Some(backtrace) = e.backtrace() {
-        eprintln!("backtrace: {:?}", backtrace);
+    if let Some(ref backtrace) = backtrace {
+        fields.push(("backtrace", backtrace.as_str()));
     }
+    log::error(msg, &fields);
+}
+
+/// Count a line we couldn't make sense of, and log it if we're debugging.
+fn reject_line(c_lines_rejected: &IntCounterVec, reason: &str, line: &str) {
+    c_lines_rejected.with_label_values(&[reason]).inc();
+    log::limited("rejected_line", log::Level::Debug, "Rejected trace line", &[("reason", reason), ("line", line)]);
 }
 
 const BUFSIZE : usize = 10 * 1024 * 1024;
 
+// Set by the SIGHUP handler, reset by the main loop once it has reloaded
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Read the list of collectors from a file, one per line or separated by commas.
+fn read_collectors_file(path: &str) -> Result<Vec<String>> {
+    let contents = fs::read_to_string(path)
+        .chain_err(|| format!("could not read {}", path))?;
+    Ok(contents
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|collector| !collector.is_empty())
+        .map(String::from)
+        .collect())
+}
+
 // Buckets for queue/disk/total time histograms, in ms
 const TIME_HISTOGRAM_BUCKETS: [f64; 17] = [
     0.01,  0.025,  0.05,  0.075,
@@ -54,97 +111,437 @@ const SIZE_HISTOGRAM_BUCKETS : [u64; 8] = [
     4, 8, 16, 32, 64, 128, 256, 512
 ];
 
+// Buckets for seek distance histograms, in kib
+const SEEK_HISTOGRAM_BUCKETS : [u64; 9] = [
+    0, 4, 64, 1024, 16 * 1024, 256 * 1024, 4 * 1024 * 1024, 64 * 1024 * 1024, 1024 * 1024 * 1024
+];
+
+/// The size class label for a request, based on the request size histogram buckets.
+fn size_class(bytes: u64) -> String {
+    match SIZE_HISTOGRAM_BUCKETS.iter().find(|kib| bytes <= *kib * 1024) {
+        Some(kib) => format!("{}k", kib),
+        None => format!(">{}k", SIZE_HISTOGRAM_BUCKETS[SIZE_HISTOGRAM_BUCKETS.len() - 1])
+    }
+}
+
+/// Push our device and rwbs filters into the kernel.
+///
+/// This is just an optimization, we filter in userspace too. So if the kernel
+/// doesn't like our filter (older kernels don't support globs), just say so.
+fn apply_kernel_filter(instance: &ktrace::Instance, device_paths: &dev::DevicePaths) {
+    let (include, exclude) = device_paths.filter_devts();
+    let filter = ktrace::build_filter(&include.unwrap_or_default(), &exclude);
+    if let Err(err) = instance.set_filter(&filter) {
+        print_error("Could not set kernel trace filter, filtering in userspace only", &err);
+    }
+}
+
+/// Everything that can be configured.
+struct Settings {
+    log_level: log::Level,
+    log_format: log::Format,
+    mount_tracefs: bool,
+    instance: String,
+    buffer_size_kb: Option<u64>,
+    privileges: privs::Options,
+    devices: dev::Options,
+    options: Options,
+}
+
+/// Settings for the main loop.
+struct Options {
+    port: u16,
+    events: Vec<&'static str>,
+    zone_type_label: bool,
+    regions: bool,
+    // Max distance in sectors for near-sequential requests, if access patterns are enabled
+    near_sequential: Option<u64>,
+    alignment: bool,
+    stripe_size: Option<u64>,   // in bytes
+    size_classes: bool,
+}
+
+// How often to check the ring buffer stats for lost events
+const TRACE_STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Bring a counter up to date with a value the kernel counts for us.
+fn sync_counter(counter: &IntCounter, value: u64) {
+    let current = counter.get() as u64;
+    if value > current {
+        counter.inc_by((value - current) as i64);
+    }
+}
+
+fn run(settings: Settings, instance: &ktrace::Instance, mut device_paths: dev::DevicePaths) -> Result<()> {
+    let Settings { mut options, mut buffer_size_kb, .. } = settings;
 
-fn run(port: u16) -> Result<()> {
     // Initialize ^c handler
     let running = Arc::new(AtomicBool::new(true));
     let running_clone = running.clone();
 
     ctrlc::set_handler(move || {
         running_clone.store(false, Ordering::SeqCst);
-    }).expect("Error setting Ctrl-C handler");
+    }).chain_err(|| "Error setting Ctrl-C handler")?;
 
     // Set up Prometheus registry and histograms
+    let mut labels_optype = device_paths.label_names();
+    labels_optype.push("optype");
+    if options.zone_type_label {
+        labels_optype.push("zone_type");
+    }
+    let mut labels_direction = labels_optype.clone();
+    labels_direction.push("direction");
+    let mut labels_region = labels_optype.clone();
+    labels_region.push("region");
+    let mut labels_pattern = labels_optype.clone();
+    labels_pattern.push("pattern");
+    let mut labels_zone_op = device_paths.label_names();
+    labels_zone_op.push("op");
+    let mut labels_size_class = labels_optype.clone();
+    labels_size_class.push("size_class");
+    let mut labels_alignment = labels_optype.clone();
+    labels_alignment.push("alignment");
+
     let h_queue_time = register_histogram_vec!(
         histogram_opts!("diskio_queue_time_seconds", "Time spent in the queue")
             .buckets(TIME_HISTOGRAM_BUCKETS.iter().map(|x| x / 1000.0).collect()),
-        &["device", "optype"]
-    ).expect("Couldn't set up queue time histogram");
+        &labels_optype
+    ).chain_err(|| "Couldn't set up queue time histogram")?;
 
     let h_disk_time = register_histogram_vec!(
         histogram_opts!("diskio_disk_time_seconds", "Time spent on the device")
             .buckets(TIME_HISTOGRAM_BUCKETS.iter().map(|x| x / 1000.0).collect()),
-        &["device", "optype"]
-    ).expect("Couldn't set up disk time histogram");
+        &labels_optype
+    ).chain_err(|| "Couldn't set up disk time histogram")?;
 
     let h_total_time = register_histogram_vec!(
         histogram_opts!("diskio_total_time_seconds", "Total time spent")
             .buckets(TIME_HISTOGRAM_BUCKETS.iter().map(|x| x / 1000.0).collect()),
-        &["device", "optype"]
-    ).expect("Couldn't set up total time histogram");
+        &labels_optype
+    ).chain_err(|| "Couldn't set up total time histogram")?;
 
     let h_queue_reqsz = register_histogram_vec!(
         histogram_opts!("diskio_queue_request_size_bytes", "Request size in bytes when queued")
             .buckets(SIZE_HISTOGRAM_BUCKETS.iter().map(|x| (*x as f64) * 1024.0).collect()),
-        &["device", "optype"]
-    ).expect("Couldn't set up queue request size histogram");
+        &labels_optype
+    ).chain_err(|| "Couldn't set up queue request size histogram")?;
 
     let h_disk_reqsz = register_histogram_vec!(
         histogram_opts!("diskio_disk_request_size_bytes", "Request size in bytes when sent to disk")
             .buckets(SIZE_HISTOGRAM_BUCKETS.iter().map(|x| (*x as f64) * 1024.0).collect()),
-        &["device", "optype"]
-    ).expect("Couldn't set up disk request size histogram");
+        &labels_optype
+    ).chain_err(|| "Couldn't set up disk request size histogram")?;
+
+    let h_bio_latency = register_histogram_vec!(
+        histogram_opts!("diskio_bio_latency_seconds", "Time from bio submission to completion")
+            .buckets(TIME_HISTOGRAM_BUCKETS.iter().map(|x| x / 1000.0).collect()),
+        &labels_optype
+    ).chain_err(|| "Couldn't set up bio latency histogram")?;
+
+    let h_region_time = register_histogram_vec!(
+        histogram_opts!("diskio_region_total_time_seconds", "Total time spent, by LBA region (only with --lba-regions)")
+            .buckets(TIME_HISTOGRAM_BUCKETS.iter().map(|x| x / 1000.0).collect()),
+        &labels_region
+    ).chain_err(|| "Couldn't set up region time histogram")?;
+
+    let h_pattern_time = register_histogram_vec!(
+        histogram_opts!("diskio_pattern_total_time_seconds", "Total time spent, by access pattern (only with --access-pattern)")
+            .buckets(TIME_HISTOGRAM_BUCKETS.iter().map(|x| x / 1000.0).collect()),
+        &labels_pattern
+    ).chain_err(|| "Couldn't set up access pattern time histogram")?;
+
+    let h_seek_distance = register_histogram_vec!(
+        histogram_opts!("diskio_seek_distance_bytes", "Distance from the end of the previous request (only with --access-pattern)")
+            .buckets(SEEK_HISTOGRAM_BUCKETS.iter().map(|x| (*x as f64) * 1024.0).collect()),
+        &labels_optype
+    ).chain_err(|| "Couldn't set up seek distance histogram")?;
+
+    let h_size_class_time = register_histogram_vec!(
+        histogram_opts!("diskio_size_class_total_time_seconds", "Total time spent, by request size (only with --size-classes)")
+            .buckets(TIME_HISTOGRAM_BUCKETS.iter().map(|x| x / 1000.0).collect()),
+        &labels_size_class
+    ).chain_err(|| "Couldn't set up size class time histogram")?;
+
+    let c_misaligned = register_int_counter_vec!(
+        "diskio_misaligned_requests_total",
+        "Requests sent to disk that do not start and end on a physical block or stripe boundary",
+        &labels_alignment
+    ).chain_err(|| "Couldn't set up misaligned requests counter")?;
+
+    let c_bios_queued = register_int_counter_vec!(
+        "diskio_bios_queued_total",
+        "Bios submitted to the block layer (only with the merges or bios collectors)",
+        &labels_optype
+    ).chain_err(|| "Couldn't set up queued bios counter")?;
+
+    let c_bio_merges = register_int_counter_vec!(
+        "diskio_bio_merges_total",
+        "Bios merged into an existing request (only with the merges collector)",
+        &labels_direction
+    ).chain_err(|| "Couldn't set up bio merges counter")?;
+
+    let c_bio_splits = register_int_counter_vec!(
+        "diskio_bio_splits_total",
+        "Bios that had to be split (only with the splits collector)",
+        &labels_optype
+    ).chain_err(|| "Couldn't set up bio splits counter")?;
+
+    let c_requeues = register_int_counter_vec!(
+        "diskio_requeues_total",
+        "Requests put back into the queue by the driver (only with the requeues collector)",
+        &labels_optype
+    ).chain_err(|| "Couldn't set up requeues counter")?;
+
+    let c_zone_ops = register_int_counter_vec!(
+        "diskio_zone_ops_total",
+        "Zone management and other requests issued to zoned devices that aren't reads or writes",
+        &labels_zone_op
+    ).chain_err(|| "Couldn't set up zone ops counter")?;
+
+    let c_plugs = register_int_counter!(
+        "diskio_plugs_total",
+        "Times a process started batching requests in its plug list (only with the plugs collector)"
+    ).chain_err(|| "Couldn't set up plugs counter")?;
+
+    let h_unplug_requests = register_histogram!(
+        histogram_opts!("diskio_unplug_requests", "Requests submitted per unplug (only with the plugs collector)")
+            .buckets(vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0])
+    ).chain_err(|| "Couldn't set up unplug histogram")?;
+
+    let c_lines_parsed = register_int_counter!(
+        "lagerist_lines_parsed_total",
+        "Lines read from trace_pipe that were parsed successfully"
+    ).chain_err(|| "Couldn't set up parsed lines counter")?;
+
+    let c_lines_rejected = register_int_counter_vec!(
+        "lagerist_lines_rejected_total",
+        "Lines read from trace_pipe that could not be parsed",
+        &["reason"]
+    ).chain_err(|| "Couldn't set up rejected lines counter")?;
+
+    let c_unmatched_completions = register_int_counter!(
+        "lagerist_unmatched_completions_total",
+        "Completions for which we did not see the insertion or issuance"
+    ).chain_err(|| "Couldn't set up unmatched completions counter")?;
+
+    let c_bytes_read = register_int_counter!(
+        "lagerist_trace_read_bytes_total",
+        "Bytes read from trace_pipe"
+    ).chain_err(|| "Couldn't set up bytes read counter")?;
+
+    let h_read_loop_duration = register_histogram!(
+        histogram_opts!("lagerist_read_loop_duration_seconds", "Time spent reading and processing a batch of trace events")
+            .buckets(TIME_HISTOGRAM_BUCKETS.iter().map(|x| x / 1000.0).collect())
+    ).chain_err(|| "Couldn't set up read loop duration histogram")?;
+
+    register_int_gauge_vec!(
+        "lagerist_build_info",
+        "Lagerist version, always 1",
+        &["version"]
+    ).chain_err(|| "Couldn't set up build info gauge")?
+        .with_label_values(&[env!("CARGO_PKG_VERSION")])
+        .set(1);
 
     let g_insertions_len = register_gauge!(
         "insertions_hashmap_len",
          "Entries in the 'insertions' hashmap (updated every 10m)"
-    ).expect("Couldn't set up insertions gauge");
+    ).chain_err(|| "Couldn't set up insertions gauge")?;
 
     let g_issuances_len = register_gauge!(
         "issuances_hashmap_len",
          "Entries in the 'issuances' hashmap (updated every 10m)"
-    ).expect("Couldn't set up issuances gauge");
+    ).chain_err(|| "Couldn't set up issuances gauge")?;
 
-    let trace_pipe_fd = unsafe {
-        libc::open(
-            CString::new(ktrace::socket_path()).unwrap().as_ptr(),
-            libc::O_RDONLY | libc::O_NONBLOCK
-        )
-    };
+    let c_trace_overrun = register_int_counter_vec!(
+        "lagerist_trace_overrun_total",
+        "Trace events overwritten in the ring buffer before we could read them",
+        &["cpu"]
+    ).chain_err(|| "Couldn't set up trace overrun counter")?;
 
-    let listener = TcpListener::bind(format!(":::{}", port))
-        .chain_err(|| "Could not start server")?;
-    listener.set_nonblocking(true)
-        .chain_err(|| "Could not set nonblocking")?;
+    let c_trace_commit_overrun = register_int_counter_vec!(
+        "lagerist_trace_commit_overrun_total",
+        "Trace events lost because nested events wrapped around the ring buffer",
+        &["cpu"]
+    ).chain_err(|| "Couldn't set up trace commit overrun counter")?;
 
-    let mut clients = vec![];
+    let c_trace_dropped = register_int_counter_vec!(
+        "lagerist_trace_dropped_events_total",
+        "Trace events dropped because the ring buffer was full",
+        &["cpu"]
+    ).chain_err(|| "Couldn't set up trace dropped events counter")?;
 
-    let mut pollfds = vec![
+    let g_trace_entries = register_int_gauge_vec!(
+        "lagerist_trace_buffer_entries",
+        "Trace events in the ring buffer waiting to be read",
+        &["cpu"]
+    ).chain_err(|| "Couldn't set up trace buffer entries gauge")?;
+
+    apply_kernel_filter(instance, &device_paths);
+
+    let mut trace_pipe = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(instance.socket_path())
+        .chain_err(|| ErrorKind::TracePipe(instance.socket_path()))?;
+
+    // Scrapes are served from their own thread, so they can't hold up reading the trace
+    let scrape_metrics = http::ScrapeMetrics::register()?;
+    let server = http::Server::bind(options.port, scrape_metrics.clone())?;
+
+    // Everything that needs root is open now
+    privs::drop_privileges(&settings.privileges)
+        .chain_err(|| "Could not drop privileges")?;
+    if let Some((ref user, _, _)) = settings.privileges.user {
+        log::debug("Dropped privileges", &[("user", user)]);
+    }
+    if settings.privileges.seccomp {
+        privs::apply_seccomp()?;
+    }
+    // The server gets rid of the capabilities we've kept on its own
+    let mut server = server.spawn()?;
+
+    let mut pollfds = [
         libc::pollfd {
-            //fd:      stream.as_raw_fd(),
-            fd:      trace_pipe_fd,
+            fd:      trace_pipe.as_raw_fd(),
             events:  libc::POLLIN,
             revents: 0
         },
         libc::pollfd {
-            fd:      listener.as_raw_fd(),
+            fd:      device_paths.uevent_fd(),
             events:  libc::POLLIN,
             revents: 0
+        },
+        libc::pollfd {
+            fd:      device_paths.mounts_fd(),
+            events:  libc::POLLPRI,
+            revents: 0
         }
     ];
 
-    let mut contents = vec![0u8; BUFSIZE];
+    let mut line_reader = linereader::LineReader::new(BUFSIZE);
 
-    let mut insertions = HashMap::new();
-    let mut issuances = HashMap::new();
-    let mut device_paths = dev::DevicePaths::new();
+    let mut insertions = inflight::InFlight::with_merges();
+    let mut issuances = inflight::InFlight::new();
+    // Requests that have been requeued: when they are inserted again, we want
+    // to keep the original insertion time instead of overwriting it
+    let mut requeued = HashSet::new();
+    // Bios are only tracked if we also see their completion, otherwise the
+    // submissions would just pile up until the next cleanup
+    let mut trace_bios = options.events.contains(&"block_bio_complete");
+    let mut bio_submissions = inflight::InFlight::new();
+    let mut access_patterns = options.near_sequential.map(pattern::AccessPatterns::new);
 
     let mut next_cleanup = 0.0;
+    let mut next_trace_stats = Instant::now();
+    let watchdog_interval = systemd::watchdog_interval();
+    let mut next_watchdog = Instant::now();
+
+    // Tracing is set up and we're accepting connections
+    log::info("Ready", &[("instance", instance.path())]);
+    systemd::notify("READY=1");
 
     while running.load(Ordering::SeqCst) {
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            // Change what we can without tearing down the instance, so that we
+            // keep requests in flight and don't reset any counters
+            match load_settings() {
+                Ok(new) => {
+                    log::init(new.log_level, new.log_format);
+                    if new.instance != settings.instance || new.mount_tracefs != settings.mount_tracefs {
+                        log::warn("Changing the ktrace instance or --mount-tracefs needs a restart", &[]);
+                    }
+                    if new.privileges != settings.privileges {
+                        log::warn("Changing --user, --keep-caps or --seccomp needs a restart", &[]);
+                    }
+                    if new.buffer_size_kb != buffer_size_kb {
+                        // Without a size, we leave the buffer as it is
+                        if let Some(size) = new.buffer_size_kb {
+                            match instance.set_buffer_size(size) {
+                                Ok(()) => buffer_size_kb = new.buffer_size_kb,
+                                Err(err) => print_error("Could not resize the ring buffer", &err)
+                            }
+                        }
+                    }
+
+                    // The label names are baked into the metrics we've registered
+                    let mut devices = new.devices;
+                    let mut new_options = new.options;
+                    let current = device_paths.options();
+                    if devices.kernel_name_label != current.kernel_name_label
+                        || devices.mountpoint_label != current.mountpoint_label
+                        || devices.zone_type_label != current.zone_type_label {
+                        log::warn("Adding or removing labels needs a restart, keeping the current ones", &[]);
+                        devices.kernel_name_label = current.kernel_name_label;
+                        devices.mountpoint_label = current.mountpoint_label;
+                        devices.zone_type_label = current.zone_type_label;
+                        new_options.zone_type_label = options.zone_type_label;
+                    }
+                    device_paths.set_options(devices);
+                    apply_kernel_filter(instance, &device_paths);
+
+                    match instance.set_events(&new_options.events) {
+                        Ok(()) => {
+                            trace_bios = new_options.events.contains(&"block_bio_complete");
+                            if !trace_bios {
+                                bio_submissions = inflight::InFlight::new();
+                            }
+                        },
+                        Err(err) => {
+                            print_error("Could not change the traced events", &err);
+                            new_options.events = options.events.clone();
+                        }
+                    }
+                    if new_options.near_sequential != options.near_sequential {
+                        access_patterns = new_options.near_sequential.map(pattern::AccessPatterns::new);
+                    }
+
+                    if new_options.port != options.port {
+                        if server.socket_activated {
+                            log::warn("The listening socket is managed by systemd, ignoring the port", &[]);
+                            new_options.port = options.port;
+                        } else {
+                            // Bind the new port first, so we keep serving if that fails
+                            match http::Server::bind(new_options.port, scrape_metrics.clone()) {
+                                Ok(new_server) => {
+                                    server.stop()?;
+                                    server = new_server.spawn()?;
+                                },
+                                Err(err) => {
+                                    print_error("Could not listen on the new port", &err);
+                                    new_options.port = options.port;
+                                }
+                            }
+                        }
+                    }
+                    options = new_options;
+                    log::info("Reloaded settings", &[]);
+                },
+                Err(err) => print_error("Could not reload settings, keeping the current ones", &err)
+            }
+        }
+        // Since we wake up at least every 100ms, this is often enough
+        if let Some(interval) = watchdog_interval {
+            if Instant::now() >= next_watchdog {
+                systemd::notify("WATCHDOG=1");
+                next_watchdog = Instant::now() + interval;
+            }
+        }
+        // If any of these go up, completions will fail to pair and the latency data is incomplete
+        if Instant::now() >= next_trace_stats {
+            match instance.cpu_stats() {
+                Ok(cpu_stats) => for stats in cpu_stats {
+                    sync_counter(&c_trace_overrun.with_label_values(&[&stats.cpu]), stats.overrun);
+                    sync_counter(&c_trace_commit_overrun.with_label_values(&[&stats.cpu]), stats.commit_overrun);
+                    sync_counter(&c_trace_dropped.with_label_values(&[&stats.cpu]), stats.dropped_events);
+                    g_trace_entries.with_label_values(&[&stats.cpu]).set(stats.entries as i64);
+                },
+                Err(err) => print_error("Could not read ring buffer stats", &err)
+            }
+            next_trace_stats = Instant::now() + TRACE_STATS_INTERVAL;
+        }
         let poll_result = unsafe {
             libc::poll(
-                &mut pollfds[0] as *mut libc::pollfd,
+                pollfds.as_mut_ptr(),
                 pollfds.len() as u64,
                 100
             )
@@ -160,66 +557,61 @@ fn run(port: u16) -> Result<()> {
                 bail!("Couldn't poll: {:?}", err);
             }
         }
+        // Check for devices coming and going. Do this first so that events from a
+        // new device don't get attributed to the one that had its major:minor before.
+        if pollfds[1].revents & libc::POLLIN != 0 && device_paths.handle_uevents() {
+            // Included or excluded devices may have come or gone
+            apply_kernel_filter(instance, &device_paths);
+        }
+        if pollfds[2].revents & libc::POLLPRI != 0 {
+            device_paths.handle_mount_change();
+        }
         // Check for new data on the trace_pipe
         if pollfds[0].revents & libc::POLLIN != 0 {
-            let data = {
-                let mut read_pos: usize = 0;
-                loop {
-                    let bytes_read = unsafe {
-                        libc::read(
-                            trace_pipe_fd,
-                            contents.as_mut_ptr().add(read_pos) as *mut libc::c_void,
-                            BUFSIZE - 1
-                        ) as isize
-                    };
-                    if bytes_read == -1 {
-                        let err = std::io::Error::last_os_error();
-                        match err.kind() {
-                            std::io::ErrorKind::Interrupted => continue, // retry
-                            std::io::ErrorKind::WouldBlock  => break,    // done
-                            _ => bail!("Couldn't read: {:?}", err)
-                        }
-                    }
-                    // bytes_read cannot be < 0 at this point - the only negative value
-                    // that it may return is -1, and we caught that above
-                    assert!(bytes_read >= 0, "read a negative amount of bytes");
-                    read_pos += bytes_read as usize;
-                }
-                String::from_utf8_lossy(&contents[..read_pos])
-            };
+            let read_loop_start = Instant::now();
+            // If the buffer fills up, we process what we have and leave the rest in
+            // the kernel until the next iteration, so we get to serve clients in between.
+            let bytes_read = line_reader.fill(&mut trace_pipe)
+                .chain_err(|| ErrorKind::TracePipe(instance.socket_path()))?;
+            c_bytes_read.inc_by(bytes_read as i64);
+            let data = line_reader.take_lines();
+            sync_counter(&c_lines_rejected.with_label_values(&["too_long"]), line_reader.overlong_lines());
             for line in data.lines() {
-                let words: Vec<&str> = line.split_ascii_whitespace().collect();
                 // The definition seems to be from here:
                 // https://github.com/torvalds/linux/blob/master/include/trace/events/block.h#L175
                 // Looks like the fields in this TP_printk are words[5:]; words[0:4] seem to be constant.
-                // Unfortunately, words[0] can contain whitespace. m(
-                // Since we don't really need words[0], look for a subslice that starts at a position
-                // such that words[1] contains the number in brackets.
-                let mut start = None;
-                for (idx, word) in words.iter().enumerate() {
-                    if word.starts_with("[") {
-                        start = Some(idx - 1);
-                        break;
-                    }
-                }
-                if start.is_none() {
-                    eprintln!("Malformatted line (can't find '['): {}", line);
-                    continue;
-                }
-                let words = &words[start.unwrap()..];
-                //dbg!(words);
-                // Get time from words[3]
-                // Unfortunately there's a : at the end, so cut that away first
-                let time = match words[3][..words[3].len() -1].parse::<f64>() {
-                    Ok(t) => t,
-                    Err(_) => {
-                        eprintln!("Malformatted line (invalid time): {}", line);
+                // Unfortunately, the task name in front can contain whitespace and whatever else. m(
+                let parsed = match traceline::parse(line) {
+                    Ok(parsed) => parsed,
+                    Err(reason) => {
+                        reject_line(&c_lines_rejected, reason, line);
                         continue;
                     }
                 };
+                let (words, time, op) = (&parsed.words[..], parsed.time, parsed.op);
+
+                // Plug events are per process, they don't have a device
+                match op {
+                    "block_plug" => {
+                        c_plugs.inc();
+                        c_lines_parsed.inc();
+                        continue;
+                    },
+                    "block_unplug" => {
+                        // "[comm] nr_rq", and comm may contain spaces
+                        if let Some(Ok(nr_rq)) = words.last().map(|word| word.parse::<f64>()) {
+                            h_unplug_requests.observe(nr_rq);
+                        }
+                        c_lines_parsed.inc();
+                        continue;
+                    },
+                    _ => ()
+                }
 
-                // Op has the same : problem
-                let op = &words[4][..words[4].len() - 1];
+                if words.len() < 7 {
+                    reject_line(&c_lines_rejected, "too_short", line);
+                    continue;
+                }
                 let dev = words[5];
                 let rwbs = words[6];
 
@@ -229,152 +621,221 @@ fn run(port: u16) -> Result<()> {
 
                 // Hash table housekeeping
                 if time > next_cleanup {
-                    insertions.retain(|_, v| time < *v + 600.0 );
-                    issuances.retain( |_, v| time < *v + 600.0 );
+                    insertions.expire(time, 600.0);
+                    issuances.expire(time, 600.0);
+                    bio_submissions.expire(time, 600.0);
+                    requeued.retain(|key: &String| insertions.contains_key(key));
                     next_cleanup = time + 600.0;
                     g_insertions_len.set(insertions.len() as f64);
                     g_issuances_len.set(issuances.len() as f64);
                 }
 
+                // Zone management requests (reset, open, close, finish), but also zone
+                // appends and write zeroes requests show up as "N" on zoned devices
+                let zone_op = rwbs.contains('N') && device_paths.is_zoned(dev);
                 let optype =
                     if rwbs.contains("R") {
                         "read"
                     } else if rwbs.contains("W") {
                         "write"
+                    } else if zone_op {
+                        "zone"  // refined below, once we know the request size
+                    } else if rwbs.contains('F') || rwbs.contains('D') || rwbs.contains('N') {
+                        // Flushes, discards and such, which we ignore on purpose. These only
+                        // get here if the kernel filter isn't in place.
+                        continue;
                     } else {
-                        //eprintln!("Ignoring line (unknown optype): {}", line);
+                        reject_line(&c_lines_rejected, "unknown_optype", line);
                         continue;
                     };
 
-                let dev_path = device_paths.get_dev_path(dev);
+                if !device_paths.is_wanted(dev) {
+                    continue;
+                }
+
+                // The position of the sector field depends on the event:
+                // rq_insert and rq_issue have a request size field, and bio
+                // events don't have the "()" cmd field.
+                let sector_idx = match op {
+                    "block_rq_insert" | "block_rq_issue" => 9,
+                    "block_rq_complete" | "block_rq_requeue" => 8,
+                    _ => 7
+                };
+                if words.len() <= sector_idx + 2 {
+                    reject_line(&c_lines_rejected, "too_short", line);
+                    continue;
+                }
+                let (sector, nr_sectors) = match (
+                    words[sector_idx].parse::<u64>(),
+                    words[sector_idx + 2].parse::<u64>()
+                ) {
+                    (Ok(sector), Ok(nr_sectors)) => (sector, nr_sectors),
+                    _ => {
+                        reject_line(&c_lines_rejected, "invalid_sector", line);
+                        continue;
+                    }
+                };
+
+                // The trace doesn't say which op it is. Zone management requests carry
+                // no data, but we can't tell zone appends from write zeroes requests.
+                let optype = match optype {
+                    "zone" if nr_sectors > 0 => "other",
+                    "zone" => "zone_mgmt",
+                    _ => optype
+                };
+
+                let dev_labels = device_paths.get_labels(dev);
+                let mut labels: Vec<&str> = dev_labels.iter().map(String::as_str).collect();
+                labels.push(optype);
+                if options.zone_type_label {
+                    labels.push(device_paths.zone_type(dev, sector));
+                }
 
                 match op {
                     "block_rq_insert" => {
-                        // insert and issue ops have a request size field
                         let reqsz = words[7];
-                        let sector = words[9];
-                        let nr_sectors = words[11];
-                        let event_key = format!("{},{},{}", dev, sector, nr_sectors);
-                        insertions.insert(event_key, time);
+                        let event_key = inflight::key(dev, sector, nr_sectors);
+                        if !(requeued.remove(&event_key) && insertions.contains_key(&event_key)) {
+                            insertions.insert(dev, sector, nr_sectors, time);
+                        }
                         if let Ok(reqsz) = reqsz.parse() {
                             h_queue_reqsz
-                                .with_label_values(&[&dev_path, optype])
+                                .with_label_values(&labels)
                                 .observe(reqsz);
                         }
                     },
                     "block_rq_issue" => {
-                        // insert and issue ops have a request size field
                         let reqsz = words[7];
-                        let sector = words[9];
-                        let nr_sectors = words[11];
-                        let event_key = format!("{},{},{}", dev, sector, nr_sectors);
-                        issuances.insert(event_key, time);
+                        issuances.insert(dev, sector, nr_sectors, time);
+                        // Sectors are always 512 bytes in the trace
+                        let (start, len) = (sector * 512, nr_sectors * 512);
+                        if options.alignment {
+                            let block_size = device_paths.physical_block_size(dev);
+                            if start % block_size != 0 || len % block_size != 0 {
+                                c_misaligned
+                                    .with_label_values(&[&labels[..], &["physical_block"]].concat())
+                                    .inc();
+                            }
+                        }
+                        if let Some(stripe_size) = options.stripe_size {
+                            if start % stripe_size != 0 || len % stripe_size != 0 {
+                                c_misaligned
+                                    .with_label_values(&[&labels[..], &["stripe"]].concat())
+                                    .inc();
+                            }
+                        }
+                        if zone_op {
+                            c_zone_ops.with_label_values(&[&labels[..dev_labels.len()], &[optype]].concat()).inc();
+                        }
                         if let Ok(reqsz) = reqsz.parse() {
                             h_disk_reqsz
-                                .with_label_values(&[&dev_path, optype])
+                                .with_label_values(&labels)
                                 .observe(reqsz);
                         }
                     },
                     "block_rq_complete" => {
-                        // complete ops do not have the size field
-                        let sector = words[8];
-                        let nr_sectors = words[10];
-                        let event_key = format!("{},{},{}", dev, sector, nr_sectors);
-                        let insertion = match insertions.remove(&event_key) {
-                            Some(t) => t,
-                            None => continue
-                        };
-                        let issuance = match issuances.remove(&event_key) {
-                            Some(t) => t,
-                            None => continue
+                        let (insertion, issuance) = match (
+                            insertions.remove(dev, sector, nr_sectors),
+                            issuances.remove(dev, sector, nr_sectors)
+                        ) {
+                            (Some(insertion), Some(issuance)) => (insertion, issuance),
+                            _ => {
+                                c_unmatched_completions.inc();
+                                c_lines_parsed.inc();
+                                continue;
+                            }
                         };
                         let queue_time = issuance - insertion;
                         let disk_time  = time - issuance;
                         let total_time = queue_time + disk_time;
-                        //dbg!(&dev_path, total_time);
-                        h_queue_time.with_label_values(&[&dev_path, optype]).observe(queue_time);
-                        h_disk_time.with_label_values(&[&dev_path, optype]).observe(disk_time);
-                        h_total_time.with_label_values(&[&dev_path, optype]).observe(total_time);
+                        //dbg!(&labels, total_time);
+                        h_queue_time.with_label_values(&labels).observe(queue_time);
+                        h_disk_time.with_label_values(&labels).observe(disk_time);
+                        h_total_time.with_label_values(&labels).observe(total_time);
+                        if options.size_classes {
+                            let size_class = size_class(nr_sectors * 512);
+                            h_size_class_time
+                                .with_label_values(&[&labels[..], &[size_class.as_str()]].concat())
+                                .observe(total_time);
+                        }
+                        if options.regions {
+                            let region = device_paths.region(dev, sector);
+                            h_region_time
+                                .with_label_values(&[&labels[..], &[region.as_str()]].concat())
+                                .observe(total_time);
+                        }
+                        let pattern = access_patterns
+                            .as_mut()
+                            .and_then(|patterns| patterns.classify(dev, optype, sector, nr_sectors));
+                        if let Some((pattern, distance)) = pattern {
+                            h_pattern_time
+                                .with_label_values(&[&labels[..], &[pattern]].concat())
+                                .observe(total_time);
+                            h_seek_distance
+                                .with_label_values(&labels)
+                                .observe((distance * 512) as f64);
+                        }
+                    },
+                    "block_rq_requeue" => {
+                        // The request goes back into the queue and will be issued again,
+                        // so the issuance we have is no longer valid.
+                        issuances.remove(dev, sector, nr_sectors);
+                        requeued.insert(inflight::key(dev, sector, nr_sectors));
+                        c_requeues.with_label_values(&labels).inc();
+                    },
+                    "block_bio_queue" => {
+                        if trace_bios {
+                            bio_submissions.insert(dev, sector, nr_sectors, time);
+                        }
+                        c_bios_queued.with_label_values(&labels).inc();
+                    },
+                    "block_bio_complete" => {
+                        if let Some(submission) = bio_submissions.remove(dev, sector, nr_sectors) {
+                            h_bio_latency.with_label_values(&labels).observe(time - submission);
+                        }
+                    },
+                    "block_bio_backmerge" => {
+                        // Requests can be merged after they've been inserted, which
+                        // changes their sector range. Move them to their new key.
+                        insertions.backmerge(dev, sector, nr_sectors);
+                        c_bio_merges.with_label_values(&[&labels[..], &["back"]].concat()).inc();
+                    },
+                    "block_bio_frontmerge" => {
+                        insertions.frontmerge(dev, sector, nr_sectors);
+                        c_bio_merges.with_label_values(&[&labels[..], &["front"]].concat()).inc();
+                    },
+                    "block_split" => {
+                        // "sector / new_sector" instead of "sector + nr_sectors",
+                        // but we only need to count these
+                        c_bio_splits.with_label_values(&labels).inc();
                     },
                     _ => continue
                 }
+                c_lines_parsed.inc();
             }
-        }
-        // Check for a new incoming connection on the listener
-        if pollfds[1].revents & libc::POLLIN != 0 {
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        /*if let Ok(addr) = stream.peer_addr() {
-                            dbg!(addr);
-                        }*/
-
-                        pollfds.push(
-                            libc::pollfd {
-                                fd: stream.as_raw_fd(),
-                                events: libc::POLLIN,
-                                revents: 0
-                            }
-                        );
-                        clients.push(stream);
-                    }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        break;
-                    }
-                    Err(e) => {
-                        Err(e).chain_err(|| "Could not accept client connection")?;
-                    }
-                }
-            }
-        }
-        // Check any additional fds as client connections
-        let mut remove_client = None;
-        for (client_fd_idx, client_fd) in pollfds.iter().enumerate().skip(2) {
-            if client_fd.revents & libc::POLLIN != 0 {
-                // Find the TcpStream that this fd belongs to
-                for (client_idx, client) in clients.iter_mut().enumerate() {
-                    if client.as_raw_fd() == client_fd.fd {
-                        // Handle the request. We always use Connection:Close semantics because easier.
-                        remove_client = Some((client_fd_idx, client_idx));
-                        let mut data = [0u8; 16_384];
-                        client.read(&mut data)
-                            .chain_err(|| "Could not read client data")?;
-
-                        // We don't really care for the request, we always respond with our data
-                        let mut buffer = Vec::new();
-                        let encoder = TextEncoder::new();
-                        let metric_families = prometheus::gather();
-                        encoder.encode(&metric_families, &mut buffer).unwrap();
-                        let output = String::from_utf8(buffer).unwrap();
-                        let response = format!(
-                            "HTTP/1.1 200 OK\nContent-Type: text/plain\nContent-Length: {}\n\n{}\n",
-                            output.len(),
-                            output
-                        );
-                        client.write(response.as_bytes()).chain_err(|| "Could not send response to client")?;
-                        break;
-                    }
-                }
-            }
-        }
-        if let Some((client_fd_idx, client_idx)) = remove_client.take() {
-            pollfds.remove(client_fd_idx);
-            clients.remove(client_idx);
+            h_read_loop_duration.observe(read_loop_start.elapsed().as_secs_f64());
         }
     }
 
-    unsafe {
-        libc::close(trace_pipe_fd);
-    }
+    log::info("Stopping", &[]);
+    systemd::notify("STOPPING=1");
+    server.stop()?;
 
     Ok(())
 }
 
-fn main() {
-    let matches = App::new(env!("CARGO_PKG_NAME"))
+/// The command line interface. All long options can also be given in the config file.
+fn app() -> App<'static, 'static> {
+    App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author("Michael Ziegler <diese-addy@funzt-halt.net>")
         .about("disk IO metrics exporter")
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .takes_value(true)
+            .help("Read options from this file, and re-read it on SIGHUP")
+        )
         .arg(Arg::with_name("port")
             .short("p")
             .long("port")
@@ -382,34 +843,363 @@ fn main() {
             .help("Port number to use")
             .default_value("9789")
         )
-        .get_matches();
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .multiple(true)
+            .help("Log more details (-v for debug, -vv for trace)")
+        )
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .takes_value(true)
+            .possible_values(&["error", "warn", "info", "debug", "trace"])
+            .help("Log level (overrides -v)")
+        )
+        .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .takes_value(true)
+            .possible_values(&["auto", "text", "logfmt", "json", "journal"])
+            .help("Log format; auto uses the journal when running under systemd, and text otherwise")
+            .default_value("auto")
+        )
+        .arg(Arg::with_name("mount-tracefs")
+            .long("mount-tracefs")
+            .help("Mount tracefs on /sys/kernel/tracing if it is not mounted anywhere")
+        )
+        .arg(Arg::with_name("instance")
+            .long("instance")
+            .takes_value(true)
+            .help("Name of the ftrace instance to use")
+            .default_value(env!("CARGO_PKG_NAME"))
+        )
+        .arg(Arg::with_name("buffer-size-kb")
+            .long("buffer-size-kb")
+            .takes_value(true)
+            .help("Size of the ftrace ring buffer per CPU in KiB (default: kernel default)")
+        )
+        .arg(Arg::with_name("user")
+            .long("user")
+            .takes_value(true)
+            .value_name("USER[:GROUP]")
+            .help("Switch to this user once tracing is set up")
+        )
+        .arg(Arg::with_name("keep-caps")
+            .long("keep-caps")
+            .takes_value(true)
+            .use_delimiter(true)
+            .possible_values(&["none", "dac_override", "dac_read_search", "net_bind_service", "sys_admin"])
+            .help("Capabilities to keep after switching users; dac_override is needed to change settings \
+                   and clean up unless tracefs is writable by the user")
+            .default_value("dac_override")
+        )
+        .arg(Arg::with_name("seccomp")
+            .long("seccomp")
+            .help("Only allow the system calls needed once tracing is set up")
+        )
+        .arg(Arg::with_name("collectors")
+            .long("collectors")
+            .takes_value(true)
+            .use_delimiter(true)
+            .possible_values(&["merges", "splits", "requeues", "bios", "plugs"])
+            .help("Optional collectors to enable")
+        )
+        .arg(Arg::with_name("collectors-file")
+            .long("collectors-file")
+            .takes_value(true)
+            .help("Read the collectors to enable from this file, and re-read it on SIGHUP")
+        )
+        .arg(Arg::with_name("trace-merges")
+            .long("trace-merges")
+            .help("Trace bio merges, splits and requeues (same as --collectors merges,splits,requeues)")
+        )
+        .arg(Arg::with_name("trace-bios")
+            .long("trace-bios")
+            .help("Trace bio submission and completion to measure bio latency (same as --collectors bios)")
+        )
+        .arg(Arg::with_name("devices")
+            .long("devices")
+            .takes_value(true)
+            .use_delimiter(true)
+            .help("Only report these devices (kernel names, e.g. sda,dm-0)")
+        )
+        .arg(Arg::with_name("exclude-devices")
+            .long("exclude-devices")
+            .takes_value(true)
+            .use_delimiter(true)
+            .help("Do not report these devices (kernel names, e.g. loop0)")
+        )
+        .arg(Arg::with_name("device-level")
+            .long("device-level")
+            .takes_value(true)
+            .possible_values(&["all", "leaf", "top-level"])
+            .help("Which devices in a dm/md stack to report")
+            .default_value("all")
+        )
+        .arg(Arg::with_name("aggregate")
+            .long("aggregate")
+            .takes_value(true)
+            .possible_values(&["partition", "disk", "top-level"])
+            .help("Which device to attribute requests to")
+            .default_value("partition")
+        )
+        .arg(Arg::with_name("device-label")
+            .long("device-label")
+            .takes_value(true)
+            .possible_values(&["path", "by-id", "by-path", "by-uuid", "by-partuuid", "wwn", "serial"])
+            .help("What to use as the device label")
+            .default_value("path")
+        )
+        .arg(Arg::with_name("kernel-name-label")
+            .long("kernel-name-label")
+            .help("Add the kernel name of the device (sda, dm-0) as an extra label")
+        )
+        .arg(Arg::with_name("mountpoint-label")
+            .long("mountpoint-label")
+            .help("Add the mount points of the device (and its partitions) as an extra label")
+        )
+        .arg(Arg::with_name("zone-type-label")
+            .long("zone-type-label")
+            .help("Add the type of the zone that a request went to as an extra label (zoned devices only)")
+        )
+        .arg(Arg::with_name("lba-regions")
+            .long("lba-regions")
+            .takes_value(true)
+            .help("Break down latency by LBA region: a number of equally sized regions, or 'partitions'")
+        )
+        .arg(Arg::with_name("access-pattern")
+            .long("access-pattern")
+            .help("Classify requests as sequential, near-sequential or random")
+        )
+        .arg(Arg::with_name("near-sequential-sectors")
+            .long("near-sequential-sectors")
+            .takes_value(true)
+            .help("Max distance in sectors from the previous request to count as near-sequential")
+            .default_value("256")
+        )
+        .arg(Arg::with_name("alignment")
+            .long("alignment")
+            .help("Count requests that are not aligned to the device's physical block size")
+        )
+        .arg(Arg::with_name("stripe-size")
+            .long("stripe-size")
+            .takes_value(true)
+            .help("Count requests that are not aligned to this stripe size (in KiB)")
+        )
+        .arg(Arg::with_name("size-classes")
+            .long("size-classes")
+            .help("Break down latency by request size")
+        )
+}
 
-    let port = match matches.value_of("port").unwrap().parse::<u16>() {
-        Err(_) => {
-            eprintln!("Port argument must be a number between 1 and 65535");
-            ::std::process::exit(2);
+/// Read long options from a config file, as "name = value" or just "name" for flags.
+fn read_config_file(path: &str) -> Result<Vec<(String, Option<String>)>> {
+    let contents = fs::read_to_string(path)
+        .chain_err(|| format!("could not read {}", path))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('=') {
+            Some((name, value)) => (String::from(name.trim()), Some(String::from(value.trim()))),
+            None => (String::from(line), None)
+        })
+        .collect())
+}
+
+/// Parse the command line and the config file into settings.
+///
+/// This is done again on SIGHUP, so it must not exit on errors.
+fn load_settings() -> Result<Settings> {
+    let args: Vec<String> = std::env::args().collect();
+    let cmdline = app().get_matches_from_safe(&args)
+        .chain_err(|| "invalid command line")?;
+
+    // Options from the config file go first, so that clap sees them just like
+    // the ones on the command line. Those on the command line win.
+    let mut all_args = vec![args[0].clone()];
+    if let Some(path) = cmdline.value_of("config") {
+        for (name, value) in read_config_file(path)? {
+            if cmdline.occurrences_of(&name) > 0 {
+                continue;
+            }
+            match value.as_deref() {
+                None | Some("true") => all_args.push(format!("--{}", name)),
+                Some("false") => (),
+                Some(value) => all_args.push(format!("--{}={}", name, value))
+            }
+        }
+    }
+    all_args.extend(args.into_iter().skip(1));
+    let matches = app().get_matches_from_safe(all_args)
+        .chain_err(|| "invalid config file")?;
+
+    // possible_values already made sure these are valid
+    let log_level = match matches.value_of("log-level") {
+        Some(level) => log::Level::from_str(level).unwrap(),
+        None => match matches.occurrences_of("verbose") {
+            0 => log::Level::Info,
+            1 => log::Level::Debug,
+            _ => log::Level::Trace
         }
+    };
+    let log_format = log::Format::from_str(matches.value_of("log-format").unwrap()).unwrap();
+
+    let port = match matches.value_of("port").unwrap().parse::<u16>() {
+        Err(_) => bail!("Port argument must be a number between 1 and 65535"),
         Ok(port) => port
     };
 
-    if let Err(err) = ktrace::setup() {
-        print_error("Could not set up ktrace", &err);
-        ::std::process::exit(1);
+    // possible_values already made sure this is valid
+    let device_level = dev::DeviceLevel::from_str(matches.value_of("device-level").unwrap()).unwrap();
+    let aggregation = dev::Aggregation::from_str(matches.value_of("aggregate").unwrap()).unwrap();
+    let device_label = dev::DeviceLabel::from_str(matches.value_of("device-label").unwrap()).unwrap();
+
+    let zone_type_label = matches.is_present("zone-type-label");
+    let regions = match matches.value_of("lba-regions") {
+        Some(regions) => match dev::Regions::from_str(regions) {
+            Some(regions) => regions,
+            None => bail!("LBA regions argument must be a positive number or 'partitions'")
+        },
+        None => dev::Regions::None
+    };
+
+    let near_sequential = match matches.value_of("near-sequential-sectors").unwrap().parse::<u64>() {
+        Err(_) => bail!("Near-sequential sectors argument must be a number"),
+        Ok(sectors) => sectors
+    };
+
+    let stripe_size = match matches.value_of("stripe-size").map(|size| size.parse::<u64>()) {
+        Some(Ok(0)) | Some(Err(_)) => bail!("Stripe size argument must be a positive number"),
+        Some(Ok(kib)) => Some(kib * 1024),
+        None => None
+    };
+
+    let buffer_size_kb = match matches.value_of("buffer-size-kb").map(|size| size.parse::<u64>()) {
+        Some(Ok(0)) | Some(Err(_)) => bail!("Buffer size argument must be a positive number"),
+        Some(Ok(kib)) => Some(kib),
+        None => None
+    };
+
+    let user = match matches.value_of("user") {
+        Some(spec) => Some(privs::resolve_user(spec)?),
+        None => None
+    };
+    let keep_caps = privs::parse_caps(matches.values_of("keep-caps").unwrap())?;
+
+    let mut collectors: Vec<String> = match matches.value_of("collectors-file") {
+        Some(path) => read_collectors_file(path)
+            .chain_err(|| "Could not read collectors")?,
+        None => matches.values_of("collectors")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default()
+    };
+    if matches.is_present("trace-merges") {
+        collectors.extend(["merges", "splits", "requeues"].iter().map(|c| String::from(*c)));
+    }
+    if matches.is_present("trace-bios") {
+        collectors.push(String::from("bios"));
+    }
+    let collectors: Vec<&str> = collectors.iter().map(String::as_str).collect();
+    let events = ktrace::events_for(&collectors)
+        .chain_err(|| "Invalid collectors")?;
+
+    Ok(Settings {
+        log_level,
+        log_format,
+        mount_tracefs: matches.is_present("mount-tracefs"),
+        instance: String::from(matches.value_of("instance").unwrap()),
+        buffer_size_kb,
+        privileges: privs::Options {
+            user,
+            keep_caps,
+            seccomp: matches.is_present("seccomp"),
+        },
+        devices: dev::Options {
+            level:             device_level,
+            aggregation,
+            label:             device_label,
+            kernel_name_label: matches.is_present("kernel-name-label"),
+            mountpoint_label:  matches.is_present("mountpoint-label"),
+            zone_type_label,
+            regions,
+            include: matches.values_of("devices")
+                .map(|values| values.map(String::from).collect())
+                .unwrap_or_default(),
+            exclude: matches.values_of("exclude-devices")
+                .map(|values| values.map(String::from).collect())
+                .unwrap_or_default(),
+        },
+        options: Options {
+            port,
+            events,
+            zone_type_label,
+            regions: regions != dev::Regions::None,
+            near_sequential: if matches.is_present("access-pattern") { Some(near_sequential) } else { None },
+            alignment: matches.is_present("alignment"),
+            stripe_size,
+            size_classes: matches.is_present("size-classes"),
+        },
+    })
+}
+
+fn main() {
+    // SIGHUP would kill us before run() is up, and leave the instance behind.
+    // Until then, we just remember to reload.
+    unsafe {
+        libc::signal(libc::SIGHUP, handle_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t);
     }
 
-    let returncode =
-        if let Err(err) = run(port) {
+    // Let clap handle --help, --version and usage errors as usual
+    app().get_matches();
+
+    let settings = match load_settings() {
+        Ok(settings) => settings,
+        Err(err) => {
+            print_error("Invalid settings", &err);
+            ::std::process::exit(2);
+        }
+    };
+    log::init(settings.log_level, settings.log_format);
+
+    let tracefs = match ktrace::find_tracefs(settings.mount_tracefs) {
+        Ok(tracefs) => tracefs,
+        Err(err) => {
+            print_error("Could not find tracefs", &err);
+            ::std::process::exit(1);
+        }
+    };
+
+    log::debug("Found tracefs", &[("path", &tracefs)]);
+
+    let instance = match ktrace::Instance::setup(&tracefs, &settings.instance, &settings.options.events, settings.buffer_size_kb) {
+        Ok(instance) => instance,
+        Err(err) => {
+            print_error("Could not set up ktrace", &err);
+            ::std::process::exit(1);
+        }
+    };
+
+    // Whatever happens from here on, we need to clean up the instance.
+    // The panic hook has already printed the message if we panicked.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let device_paths = dev::DevicePaths::new(settings.devices.clone())?;
+        run(settings, &instance, device_paths)
+    }));
+    let returncode = match result {
+        Ok(Ok(())) => 0,
+        Ok(Err(err)) => {
             print_error("error", &err);
             1
-        } else {
-            0
-        };
+        },
+        Err(_) => 1
+    };
 
-    if let Err(err) = ktrace::teardown() {
+    let instance_path = String::from(instance.path());
+    if let Err(err) = instance.teardown() {
         print_error("Could not tear down ktrace", &err);
-        eprintln!(
-            "You'll probably want to rmdir /sys/kernel/debug/tracing/instances/{}",
-            env!("CARGO_PKG_NAME")
+        log::error(
+            "It will be cleaned up the next time lagerist starts, or you can rmdir it",
+            &[("instance", &instance_path)]
         );
         ::std::process::exit(1);
     }
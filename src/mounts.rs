@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+pub struct Mount {
+    pub mountpoint: String,
+    pub fstype: String,
+}
+
+/// Mount points by device, from /proc/self/mountinfo.
+///
+/// The kernel flags mountinfo with POLLPRI whenever something gets mounted,
+/// unmounted or remounted, so we keep it open to be able to poll() it.
+pub struct Mounts {
+    file: Option<File>,
+    by_dev: HashMap<String, Vec<Mount>>,  // "major,minor" -> mounts
+}
+
+fn unescape(field: &str) -> String {
+    // Spaces, tabs, newlines and backslashes are escaped as \ooo
+    let mut result = String::new();
+    let mut chars = field.chars();
+    while let Some(chr) = chars.next() {
+        if chr == '\\' {
+            let octal: String = chars.by_ref().take(3).collect();
+            match u8::from_str_radix(&octal, 8) {
+                Ok(byte) if octal.len() == 3 && !octal.starts_with('+') => result.push(byte as char),
+                _ => {
+                    result.push(chr);
+                    result.push_str(&octal);
+                }
+            }
+        } else {
+            result.push(chr);
+        }
+    }
+    result
+}
+
+/// Split a mountinfo line into major:minor, root, mount point and fstype.
+fn parse_line(line: &str) -> Option<(&str, &str, String, &str)> {
+    // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+    // The number of optional fields before the "-" varies.
+    let fields: Vec<&str> = line.split(' ').collect();
+    let separator = match fields.iter().position(|field| *field == "-") {
+        Some(idx) if idx >= 5 && fields.len() > idx + 1 => idx,
+        _ => return None
+    };
+    Some((fields[2], fields[3], unescape(fields[4]), fields[separator + 1]))
+}
+
+fn parse(mountinfo: &str) -> HashMap<String, Vec<Mount>> {
+    let mut by_dev: HashMap<String, Vec<Mount>> = HashMap::new();
+    for (majmin, root, mountpoint, fstype) in mountinfo.lines().filter_map(parse_line) {
+        // Bind mounts have a root other than "/", skip those since they'd just be duplicates
+        if root != "/" {
+            continue;
+        }
+        by_dev
+            .entry(majmin.replace(':', ","))
+            .or_default()
+            .push(Mount {
+                mountpoint,
+                fstype: String::from(fstype),
+            });
+    }
+    by_dev
+}
+
+/// Mount points of all filesystems of the given type.
+pub fn find_by_fstype(fstype: &str) -> Vec<String> {
+    match std::fs::read_to_string("/proc/self/mountinfo") {
+        Ok(mountinfo) => mountinfo.lines()
+            .filter_map(parse_line)
+            .filter(|(_, _, _, mount_fstype)| *mount_fstype == fstype)
+            .map(|(_, _, mountpoint, _)| mountpoint)
+            .collect(),
+        Err(_) => vec![]
+    }
+}
+
+impl Mounts {
+    pub fn new() -> Self {
+        let mut mounts = Self {
+            file: File::open("/proc/self/mountinfo").ok(),
+            by_dev: HashMap::new(),
+        };
+        mounts.reload();
+        mounts
+    }
+
+    /// The mountinfo fd, or -1 (which poll() ignores) if we couldn't open it.
+    pub fn fd(&self) -> RawFd {
+        self.file.as_ref().map(File::as_raw_fd).unwrap_or(-1)
+    }
+
+    pub fn reload(&mut self) {
+        if let Some(ref mut file) = self.file {
+            let mut mountinfo = String::new();
+            if file.seek(SeekFrom::Start(0)).is_ok() && file.read_to_string(&mut mountinfo).is_ok() {
+                self.by_dev = parse(&mountinfo);
+            }
+        }
+    }
+
+    pub fn get(&self, dev: &str) -> &[Mount] {
+        self.by_dev.get(dev).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+25 22 8:1 / /boot rw,relatime shared:5 master:1 - vfat /dev/sda1 rw,fmask=0022
+26 22 0:21 / /sys rw,nosuid - sysfs sysfs rw
+30 22 253:0 / /mnt/my\\040data rw,noatime - xfs /dev/mapper/vg-data rw
+31 22 253:0 /sub /srv rw,noatime shared:9 - xfs /dev/mapper/vg-data rw
+32 22 8:2 / /var/lib/tab\\011dir rw - ext4 /dev/sda2 rw
+";
+
+    #[test]
+    fn lines() {
+        let lines: Vec<_> = MOUNTINFO.lines().map(parse_line).collect();
+        assert_eq!(lines[0], Some(("8:2", "/", String::from("/"), "ext4")));
+        // Two optional fields
+        assert_eq!(lines[1], Some(("8:1", "/", String::from("/boot"), "vfat")));
+        // None at all
+        assert_eq!(lines[2], Some(("0:21", "/", String::from("/sys"), "sysfs")));
+        assert_eq!(lines[3], Some(("253:0", "/", String::from("/mnt/my data"), "xfs")));
+        assert_eq!(lines[4], Some(("253:0", "/sub", String::from("/srv"), "xfs")));
+    }
+
+    #[test]
+    fn broken_lines() {
+        assert_eq!(parse_line(""), None);
+        assert_eq!(parse_line("22 1 8:2 / / rw,relatime shared:1 ext4 /dev/sda2 rw"), None);
+        assert_eq!(parse_line("22 1 8:2 / - ext4"), None);
+        assert_eq!(parse_line("22 1 8:2 / / rw -"), None);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape("/plain"), "/plain");
+        assert_eq!(unescape("/a\\040b\\011c\\012d\\134e"), "/a b\tc\nd\\e");
+        assert_eq!(unescape("/ünïcödé\\040x"), "/ünïcödé x");
+        // Not an octal escape, keep it as it is
+        assert_eq!(unescape("/a\\x4"), "/a\\x4");
+        assert_eq!(unescape("/a\\04"), "/a\\04");
+        assert_eq!(unescape("/a\\+40"), "/a\\+40");
+    }
+
+    #[test]
+    fn by_device() {
+        let by_dev = parse(MOUNTINFO);
+        let mountpoints = |dev: &str| -> Vec<&str> {
+            by_dev[dev].iter().map(|mount| mount.mountpoint.as_str()).collect()
+        };
+        assert_eq!(mountpoints("8,2"), vec!["/", "/var/lib/tab\tdir"]);
+        assert_eq!(mountpoints("8,1"), vec!["/boot"]);
+        // The bind mount of a subdirectory is left out
+        assert_eq!(mountpoints("253,0"), vec!["/mnt/my data"]);
+        assert_eq!(by_dev["8,1"][0].fstype, "vfat");
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+/// Classifies requests as sequential, near-sequential or random, based on where
+/// the previous request completed on the same device with the same optype.
+pub struct AccessPatterns {
+    last_end: HashMap<String, u64>,   // "dev,optype" -> end sector of the last completed request
+    near_sequential: u64,             // max distance in sectors to count as near-sequential
+}
+
+impl AccessPatterns {
+    pub fn new(near_sequential: u64) -> Self {
+        Self {
+            last_end: HashMap::new(),
+            near_sequential,
+        }
+    }
+
+    /// Record a completed request and return its class and the seek distance in sectors.
+    ///
+    /// The first request we see on a device has nothing to go by, so it returns None.
+    pub fn classify(&mut self, dev: &str, optype: &str, sector: u64, nr_sectors: u64) -> Option<(&'static str, u64)> {
+        let last_end = self.last_end.insert(format!("{},{}", dev, optype), sector + nr_sectors);
+        let distance = sector.abs_diff(last_end?);
+        let class = if distance == 0 {
+            "sequential"
+        } else if distance <= self.near_sequential {
+            "near_sequential"
+        } else {
+            "random"
+        };
+        Some((class, distance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        let mut patterns = AccessPatterns::new(64);
+        assert_eq!(patterns.classify("8,0", "read", 1000, 8), None);
+        assert_eq!(patterns.classify("8,0", "read", 1008, 8), Some(("sequential", 0)));
+        assert_eq!(patterns.classify("8,0", "read", 1080, 8), Some(("near_sequential", 64)));
+        // Going backwards counts the same
+        assert_eq!(patterns.classify("8,0", "read", 1024, 8), Some(("near_sequential", 64)));
+        assert_eq!(patterns.classify("8,0", "read", 1097, 8), Some(("random", 65)));
+        assert_eq!(patterns.classify("8,0", "read", 0, 8), Some(("random", 1105)));
+    }
+
+    #[test]
+    fn devices_and_optypes_are_separate() {
+        let mut patterns = AccessPatterns::new(64);
+        assert_eq!(patterns.classify("8,0", "read", 1000, 8), None);
+        assert_eq!(patterns.classify("8,0", "write", 1008, 8), None);
+        assert_eq!(patterns.classify("8,16", "read", 1008, 8), None);
+        assert_eq!(patterns.classify("8,0", "write", 5000, 8), Some(("random", 3984)));
+        assert_eq!(patterns.classify("8,0", "read", 1008, 8), Some(("sequential", 0)));
+    }
+}
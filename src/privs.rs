@@ -0,0 +1,238 @@
+use std::ffi::{CStr, CString};
+use std::fs;
+
+use super::errors::*;
+
+// From linux/capability.h
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+pub const CAPABILITIES: [(&str, u32); 4] = [
+    ("dac_override",     1),    // write to root-owned tracefs, open block devices for zone reports
+    ("dac_read_search",  2),
+    ("net_bind_service", 10),   // listen on a port below 1024 after a reload
+    ("sys_admin",        21),
+];
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Whom to run as once tracing is set up.
+#[derive(Clone, PartialEq)]
+pub struct Options {
+    pub user: Option<(String, libc::uid_t, libc::gid_t)>,
+    pub keep_caps: Vec<u32>,
+    pub seccomp: bool,
+}
+
+/// Look up "user" or "user:group" in the user database.
+pub fn resolve_user(spec: &str) -> Result<(String, libc::uid_t, libc::gid_t)> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None)
+    };
+    let user_c = CString::new(user).chain_err(|| format!("invalid user name: {:?}", user))?;
+    let passwd = unsafe { libc::getpwnam(user_c.as_ptr()) };
+    if passwd.is_null() {
+        bail!("unknown user: {}", user);
+    }
+    let (name, uid, mut gid) = unsafe {
+        let name = CStr::from_ptr((*passwd).pw_name).to_string_lossy().into_owned();
+        (name, (*passwd).pw_uid, (*passwd).pw_gid)
+    };
+    if let Some(group) = group {
+        let group_c = CString::new(group).chain_err(|| format!("invalid group name: {:?}", group))?;
+        let grp = unsafe { libc::getgrnam(group_c.as_ptr()) };
+        if grp.is_null() {
+            bail!("unknown group: {}", group);
+        }
+        gid = unsafe { (*grp).gr_gid };
+    }
+    Ok((name, uid, gid))
+}
+
+/// Map capability names to their numbers.
+pub fn parse_caps<'a>(names: impl Iterator<Item=&'a str>) -> Result<Vec<u32>> {
+    let mut caps = vec![];
+    for name in names {
+        if name == "none" {
+            continue;
+        }
+        match CAPABILITIES.iter().find(|(cap_name, _)| *cap_name == name) {
+            Some((_, cap)) => caps.push(*cap),
+            None => bail!("unknown capability: {}", name)
+        }
+    }
+    Ok(caps)
+}
+
+fn check(result: libc::c_int, what: &str) -> Result<()> {
+    if result == -1 {
+        return Err(std::io::Error::last_os_error()).chain_err(|| String::from(what));
+    }
+    Ok(())
+}
+
+fn capset(mask: u64) -> Result<()> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let mut data = [CapData::default(); 2];
+    for (idx, data) in data.iter_mut().enumerate() {
+        let mask = (mask >> (32 * idx)) as u32;
+        data.effective = mask;
+        data.permitted = mask;
+    }
+    check(
+        unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapHeader, data.as_ptr()) as libc::c_int },
+        "could not set capabilities"
+    )
+}
+
+/// Switch to the configured user, keeping only the given capabilities.
+///
+/// The user changes for all threads. Threads that are running already lose all
+/// capabilities, but this one keeps those we're asked to, and threads started
+/// from it later on inherit them. Those that don't need them should call
+/// drop_thread_capabilities().
+pub fn drop_privileges(options: &Options) -> Result<()> {
+    let (name, uid, gid) = match options.user {
+        Some(ref user) => user.clone(),
+        None => return Ok(())
+    };
+    let kept_mask = options.keep_caps.iter().fold(0u64, |mask, cap| mask | (1 << cap));
+
+    // Make sure nothing we don't keep can ever be regained, not even by exec()
+    let last_cap = fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|last_cap| last_cap.trim().parse::<u32>().ok())
+        .unwrap_or(40);
+    for cap in 0..=last_cap {
+        if kept_mask & (1 << cap) == 0 {
+            check(unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) },
+                  "could not drop capability from the bounding set")?;
+        }
+    }
+
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) }, "could not keep capabilities")?;
+    let name_c = CString::new(name).chain_err(|| "invalid user name")?;
+    check(unsafe { libc::initgroups(name_c.as_ptr(), gid) }, "could not set supplementary groups")?;
+    check(unsafe { libc::setgid(gid) }, "could not change group")?;
+    check(unsafe { libc::setuid(uid) }, "could not change user")?;
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) }, "could not reset keepcaps")?;
+
+    // setuid() cleared the effective set, and the permitted set still has everything
+    capset(kept_mask)
+}
+
+/// Give up all capabilities in the calling thread, for good.
+pub fn drop_thread_capabilities() -> Result<()> {
+    capset(0)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod seccomp {
+    // AUDIT_ARCH_X86_64
+    pub const ARCH: u32 = 0xC000_003E;
+
+    // Too new for our libc (or missing from it on musl)
+    const SYS_STATX: libc::c_long = 332;
+    const SYS_RSEQ: libc::c_long = 334;
+    const SYS_CLONE3: libc::c_long = 435;
+
+    // From linux/filter.h
+    #[repr(C)]
+    pub struct SockFilter {
+        pub code: u16,
+        pub jt: u8,
+        pub jf: u8,
+        pub k: u32,
+    }
+
+    #[repr(C)]
+    pub struct SockFprog {
+        pub len: libc::c_ushort,
+        pub filter: *const SockFilter,
+    }
+
+    // Everything we need after setup: reading the trace, looking up devices,
+    // serving metrics, changing settings on SIGHUP and tearing down the instance
+    pub const SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_pread64,
+        libc::SYS_open, libc::SYS_openat, libc::SYS_close, libc::SYS_lseek,
+        libc::SYS_stat, libc::SYS_fstat, libc::SYS_lstat, libc::SYS_newfstatat, SYS_STATX,
+        libc::SYS_statfs, libc::SYS_fstatfs, libc::SYS_readlink, libc::SYS_readlinkat,
+        libc::SYS_getdents64, libc::SYS_fcntl, libc::SYS_flock, libc::SYS_ioctl,
+        libc::SYS_mkdir, libc::SYS_rmdir, libc::SYS_unlinkat, libc::SYS_getcwd,
+        libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mremap, libc::SYS_mprotect, libc::SYS_madvise,
+        libc::SYS_brk, libc::SYS_poll, libc::SYS_ppoll,
+        libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+        libc::SYS_socket, libc::SYS_connect, libc::SYS_bind, libc::SYS_listen,
+        libc::SYS_accept, libc::SYS_accept4, libc::SYS_recvfrom, libc::SYS_recvmsg,
+        libc::SYS_sendto, libc::SYS_sendmsg, libc::SYS_setsockopt, libc::SYS_getsockopt,
+        libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_shutdown,
+        libc::SYS_futex, libc::SYS_clone, SYS_CLONE3, libc::SYS_set_robust_list, SYS_RSEQ,
+        libc::SYS_sched_getaffinity, libc::SYS_sched_yield, libc::SYS_prlimit64,
+        libc::SYS_exit, libc::SYS_exit_group, libc::SYS_restart_syscall,
+        libc::SYS_getpid, libc::SYS_gettid, libc::SYS_tgkill, libc::SYS_getuid, libc::SYS_geteuid,
+        libc::SYS_nanosleep, libc::SYS_clock_nanosleep, libc::SYS_clock_gettime, libc::SYS_gettimeofday,
+        libc::SYS_getrandom, libc::SYS_uname, libc::SYS_capset,
+    ];
+}
+
+/// Only allow the system calls we need from here on.
+///
+/// Anything else fails with EPERM instead of killing us, so we still get to
+/// clean up the ftrace instance if we've forgotten something.
+#[cfg(target_arch = "x86_64")]
+pub fn apply_seccomp() -> Result<()> {
+    const BPF_LD_W_ABS: u16 = 0x20;     // BPF_LD | BPF_W | BPF_ABS
+    const BPF_JEQ_K: u16 = 0x15;        // BPF_JMP | BPF_JEQ | BPF_K
+    const BPF_RET_K: u16 = 0x06;        // BPF_RET | BPF_K
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: libc::c_long = 1;
+
+    let stmt = |code, k| seccomp::SockFilter { code, jt: 0, jf: 0, k };
+    let jump = |k, jt, jf| seccomp::SockFilter { code: BPF_JEQ_K, jt, jf, k };
+
+    // struct seccomp_data { int nr; __u32 arch; ... }
+    let mut filter = vec![
+        stmt(BPF_LD_W_ABS, 4),
+        jump(seccomp::ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, 0),
+    ];
+    for syscall in seccomp::SYSCALLS {
+        filter.push(jump(*syscall as u32, 0, 1));
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+
+    let prog = seccomp::SockFprog { len: filter.len() as u16, filter: filter.as_ptr() };
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) }, "could not set no_new_privs")?;
+    // TSYNC applies the filter to the threads we've already started, too
+    check(
+        unsafe {
+            libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER, SECCOMP_FILTER_FLAG_TSYNC,
+                          &prog as *const seccomp::SockFprog) as libc::c_int
+        },
+        "could not install seccomp filter"
+    )?;
+    Ok(())
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn apply_seccomp() -> Result<()> {
+    bail!("seccomp filtering is only supported on x86_64");
+}
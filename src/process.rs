@@ -0,0 +1,30 @@
+use std::fs;
+
+/// CPU time and memory usage of our own process, from /proc/self/stat.
+pub struct ProcessStats {
+    pub cpu_seconds: f64,
+    pub resident_bytes: u64,
+}
+
+impl ProcessStats {
+    pub fn read() -> Option<Self> {
+        // 1234 (lagerist) S 1 1234 ... utime stime ... rss ...
+        // comm may contain spaces and parens, so start after the last ')'.
+        let stat = fs::read_to_string("/proc/self/stat").ok()?;
+        let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_ascii_whitespace().collect();
+        // fields[0] is field 3 (state) in proc(5)
+        let utime = fields.get(11)?.parse::<u64>().ok()?;
+        let stime = fields.get(12)?.parse::<u64>().ok()?;
+        let rss_pages = fields.get(21)?.parse::<u64>().ok()?;
+        let (ticks, page_size) = unsafe {
+            (libc::sysconf(libc::_SC_CLK_TCK), libc::sysconf(libc::_SC_PAGESIZE))
+        };
+        if ticks <= 0 || page_size <= 0 {
+            return None;
+        }
+        Some(Self {
+            cpu_seconds: (utime + stime) as f64 / ticks as f64,
+            resident_bytes: rss_pages * page_size as u64,
+        })
+    }
+}
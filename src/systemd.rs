@@ -0,0 +1,101 @@
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::Duration;
+
+// The first fd passed by socket activation, see sd_listen_fds(3)
+const LISTEN_FDS_START: i32 = 3;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// The environment variable, if it was meant for us and not some parent process.
+fn env_for_pid(name: &str) -> Option<String> {
+    let pid = env::var(format!("{}_PID", name.split('_').next()?)).ok();
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    env::var(name).ok()
+}
+
+/// Tell systemd about our state, e.g. "READY=1". Does nothing if we're not run by systemd.
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(&path)
+    };
+    // If this fails, systemd will notice on its own that we're not ready or stuck
+    if let (Ok(addr), Ok(socket)) = (addr, UnixDatagram::unbound()) {
+        let _ = socket.send_to_addr(state.as_bytes(), &addr);
+    }
+}
+
+/// How often we need to ping the watchdog, if systemd wants us to.
+///
+/// That's half of WatchdogSec, as recommended by sd_watchdog_enabled(3).
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env_for_pid("WATCHDOG_USEC")?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// The listener passed to us by socket activation, if any.
+pub fn listener() -> Option<TcpListener> {
+    let fds = env_for_pid("LISTEN_FDS")?.parse::<i32>().ok()?;
+    // Don't pass these on to anything we might start
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if fds < 1 {
+        return None;
+    }
+    unsafe {
+        libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC);
+        Some(TcpListener::from_raw_fd(LISTEN_FDS_START))
+    }
+}
+
+/// Whether our stderr goes to the journal, so we can log to it directly instead.
+pub fn journal_connected() -> bool {
+    // JOURNAL_STREAM is "device:inode" of the stream connected to stderr
+    let stream = match env::var("JOURNAL_STREAM") {
+        Ok(stream) => stream,
+        Err(_) => return false
+    };
+    match fs::metadata("/proc/self/fd/2") {
+        Ok(meta) => stream == format!("{}:{}", meta.dev(), meta.ino()),
+        Err(_) => false
+    }
+}
+
+/// Send a structured entry to the journal, using the native protocol.
+///
+/// Returns false if that didn't work, so the caller can fall back to stderr.
+pub fn journal_send(fields: &[(&str, &str)]) -> bool {
+    let mut entry = Vec::new();
+    for (key, value) in fields {
+        entry.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            // Multi-line values are sent as the key, a newline, the length as a
+            // 64 bit little endian number, and then the value
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    match UnixDatagram::unbound() {
+        Ok(socket) => socket.send_to(&entry, JOURNAL_SOCKET).is_ok(),
+        Err(_) => false
+    }
+}
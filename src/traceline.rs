@@ -0,0 +1,99 @@
+use super::ktrace;
+
+/// A trace_pipe line, split into words starting at the task name's last word.
+///
+/// That makes words[1] the CPU, words[3] the timestamp and words[4] the event,
+/// followed by the event's fields.
+pub struct TraceLine<'a> {
+    pub words: Vec<&'a str>,
+    pub time: f64,
+    pub op: &'a str,
+}
+
+fn is_cpu(word: &str) -> bool {
+    match word.strip_prefix('[').and_then(|word| word.strip_suffix(']')) {
+        Some(cpu) => !cpu.is_empty() && cpu.bytes().all(|byte| byte.is_ascii_digit()),
+        None => false
+    }
+}
+
+fn parse_at(words: &[&str], start: usize) -> Result<(f64, usize), &'static str> {
+    if words.len() < start + 5 {
+        return Err("too_short");
+    }
+    let time = match words[start + 3].strip_suffix(':').map(str::parse::<f64>) {
+        Some(Ok(time)) => time,
+        _ => return Err("invalid_time")
+    };
+    match words[start + 4].strip_suffix(':') {
+        Some(op) if ktrace::is_known_event(op) => Ok((time, start)),
+        _ => Err("unknown_op")
+    }
+}
+
+/// Split a line like "dd-1234 [002] .... 12345.678901: block_rq_issue: 8,0 W ...".
+///
+/// The task name can be set by any process and contain spaces, brackets and
+/// anything else, so we take the first position where the CPU, timestamp and
+/// event make sense. If there is none, the error is the reason to reject the
+/// line for, going by the first word that looks like a CPU.
+pub fn parse(line: &str) -> Result<TraceLine<'_>, &'static str> {
+    let words: Vec<&str> = line.split_ascii_whitespace().collect();
+    let mut first_err = None;
+    for start in 0..words.len().saturating_sub(1) {
+        if !is_cpu(words[start + 1]) {
+            continue;
+        }
+        match parse_at(&words, start) {
+            Ok((time, start)) => {
+                let op = words[start + 4].trim_end_matches(':');
+                let words = words[start..].to_vec();
+                return Ok(TraceLine { words, time, op });
+            },
+            Err(reason) => {
+                first_err.get_or_insert(reason);
+            }
+        }
+    }
+    Err(first_err.unwrap_or("no_bracket"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = include_str!("../disk_trace.txt");
+
+    #[test]
+    fn sample_trace() {
+        for line in TRACE.lines() {
+            let parsed = parse(line).unwrap_or_else(|reason| panic!("{}: {}", reason, line));
+            assert!(parsed.time > 25070.0, "{}", line);
+            assert!(parsed.op.starts_with("block_rq_"), "{}", line);
+            assert_eq!(parsed.words[5], "8,0", "{}", line);
+        }
+    }
+
+    #[test]
+    fn hostile_task_names() {
+        for comm in &["a [ b é c", "[000] x 1.0: block_rq_issue", "é", "a] [1é]: [", "x-1 [0] é: é:"] {
+            let line = format!("{}-42 [001] .... 12.5: block_rq_issue: 8,0 W 4096 () 100 + 8 [{}]", comm, comm);
+            let parsed = parse(&line).unwrap();
+            assert_eq!(parsed.time, 12.5, "{}", comm);
+            assert_eq!(parsed.op, "block_rq_issue");
+            assert_eq!(parsed.words[5], "8,0");
+            assert_eq!(parsed.words[6], "W");
+        }
+    }
+
+    #[test]
+    fn rejections() {
+        assert_eq!(parse("").err(), Some("no_bracket"));
+        assert_eq!(parse("é [ é").err(), Some("no_bracket"));
+        assert_eq!(parse("dd-1 [001] ....").err(), Some("too_short"));
+        assert_eq!(parse("dd-1 [001] .... é block_rq_issue: 8,0").err(), Some("invalid_time"));
+        assert_eq!(parse("dd-1 [001] .... 12.5 block_rq_issue: 8,0").err(), Some("invalid_time"));
+        assert_eq!(parse("dd-1 [001] .... 12.5: block_rq_issue 8,0").err(), Some("unknown_op"));
+        assert_eq!(parse("dd-1 [001] .... 12.5: sched_switch: é").err(), Some("unknown_op"));
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+
+/// A netlink socket that receives kernel uevents, so we notice devices coming and going.
+pub struct UeventSocket {
+    fd: RawFd,
+}
+
+/// A uevent from the block subsystem.
+pub struct Uevent {
+    pub action: String,
+    pub dev: Option<String>,    // "major,minor", like in the trace
+    pub name: Option<String>,   // kernel name, like "sda1"
+}
+
+impl UeventSocket {
+    pub fn open() -> std::io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT
+            )
+        };
+        if fd == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = 1; // kernel events, as opposed to those re-broadcast by udev
+        let bind_result = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t
+            )
+        };
+        if bind_result == -1 {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::close(fd); }
+            return Err(err);
+        }
+        Ok(Self { fd })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// Read all pending uevents and return those from the block subsystem.
+    pub fn read_events(&self) -> Vec<Uevent> {
+        let mut events = vec![];
+        let mut buf = [0u8; 8192];
+        loop {
+            let bytes_read = unsafe {
+                libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+            };
+            if bytes_read <= 0 {
+                // Either WouldBlock (we're done) or an error we can't do anything about
+                break;
+            }
+            // The message is "action@devpath\0KEY=value\0KEY=value\0..."
+            let fields: HashMap<&str, &str> = buf[..bytes_read as usize]
+                .split(|byte| *byte == 0)
+                .filter_map(|field| std::str::from_utf8(field).ok())
+                .filter_map(|field| {
+                    let mut parts = field.splitn(2, '=');
+                    Some((parts.next()?, parts.next()?))
+                })
+                .collect();
+            if fields.get("SUBSYSTEM") != Some(&"block") {
+                continue;
+            }
+            events.push(Uevent {
+                action: String::from(*fields.get("ACTION").unwrap_or(&"")),
+                dev: match (fields.get("MAJOR"), fields.get("MINOR")) {
+                    (Some(major), Some(minor)) => Some(format!("{},{}", major, minor)),
+                    _ => None
+                },
+                name: fields.get("DEVNAME").map(|name| String::from(*name)),
+            });
+        }
+        events
+    }
+}
+
+impl Drop for UeventSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
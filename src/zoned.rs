@@ -0,0 +1,82 @@
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+// _IOWR(0x12, 130, struct blk_zone_report)
+const BLKREPORTZONE: u32 = 0xC010_1282;
+
+// Zones fetched per ioctl call
+const ZONES_PER_REPORT: usize = 4096;
+
+// struct blk_zone_report is 16 bytes, followed by struct blk_zone of 64 bytes each
+const REPORT_HEADER_SIZE: usize = 16;
+const ZONE_SIZE: usize = 64;
+
+/// Zone layout of a zoned block device, as consecutive ranges of zones of the same type.
+pub struct Zones {
+    ranges: Vec<(u64, u64, &'static str)>,  // start sector, end sector, zone type
+}
+
+fn zone_type_name(zone_type: u8) -> &'static str {
+    match zone_type {
+        1 => "conventional",
+        2 => "seq_write_required",
+        3 => "seq_write_preferred",
+        _ => "unknown"
+    }
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_ne_bytes(bytes)
+}
+
+impl Zones {
+    /// Ask the kernel for the zones of /dev/<name>.
+    pub fn report(name: &str) -> std::io::Result<Self> {
+        let file = File::open(format!("/dev/{}", name))?;
+        let mut buf = vec![0u8; REPORT_HEADER_SIZE + ZONES_PER_REPORT * ZONE_SIZE];
+        let mut ranges: Vec<(u64, u64, &'static str)> = vec![];
+        let mut sector = 0u64;
+        loop {
+            // struct blk_zone_report { __u64 sector; __u32 nr_zones; __u32 flags; ... }
+            buf[12..16].copy_from_slice(&0u32.to_ne_bytes());
+            buf[0..8].copy_from_slice(&sector.to_ne_bytes());
+            buf[8..12].copy_from_slice(&(ZONES_PER_REPORT as u32).to_ne_bytes());
+            let result = unsafe {
+                libc::ioctl(file.as_raw_fd(), BLKREPORTZONE as _, buf.as_mut_ptr())
+            };
+            if result == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mut nr_bytes = [0u8; 4];
+            nr_bytes.copy_from_slice(&buf[8..12]);
+            let nr_zones = u32::from_ne_bytes(nr_bytes) as usize;
+            if nr_zones == 0 {
+                break;
+            }
+            for idx in 0..nr_zones {
+                // struct blk_zone { __u64 start; __u64 len; __u64 wp; __u8 type; ... }
+                let offset = REPORT_HEADER_SIZE + idx * ZONE_SIZE;
+                let start = read_u64(&buf, offset);
+                let len = read_u64(&buf, offset + 8);
+                let zone_type = zone_type_name(buf[offset + 24]);
+                match ranges.last_mut() {
+                    Some(last) if last.2 == zone_type && last.1 == start => last.1 = start + len,
+                    _ => ranges.push((start, start + len, zone_type))
+                }
+                sector = start + len;
+            }
+        }
+        Ok(Self { ranges })
+    }
+
+    /// The type of the zone that contains this sector.
+    pub fn zone_type(&self, sector: u64) -> &'static str {
+        let idx = self.ranges.partition_point(|range| range.1 <= sector);
+        match self.ranges.get(idx) {
+            Some(range) if range.0 <= sector => range.2,
+            _ => ""
+        }
+    }
+}